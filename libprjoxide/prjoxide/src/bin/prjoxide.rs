@@ -11,10 +11,13 @@ use prjoxide::bitstream::*;
 use prjoxide::chip::*;
 use prjoxide::database::*;
 use prjoxide::fasmparse::*;
+use prjoxide::multiboot::*;
+use prjoxide::svf::*;
 
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::*;
+use std::process::ExitCode;
 
 use include_dir::{include_dir, Dir};
 
@@ -33,6 +36,26 @@ enum SubCommand {
     Pack(Pack),
     #[clap(about = "unpack a bitstream into FASM")]
     Unpack(Unpack),
+    #[clap(about = "apply FASM on top of an existing bitstream")]
+    Patch(Patch),
+    #[clap(about = "print an annotated listing of bitstream commands")]
+    Disasm(Disasm),
+    #[clap(about = "write a SVF file for programming over JTAG")]
+    Svf(Svf),
+    #[clap(about = "write the expected readback image for FASM")]
+    Readback(Readback),
+    #[clap(about = "show the tiles and features using a configuration bit")]
+    Explain(Explain),
+    #[clap(about = "compare a readback image against a bitstream or FASM")]
+    Verify(Verify),
+    #[clap(about = "show the features that differ between two bitstreams")]
+    Diff(Diff),
+    #[clap(about = "compare the canonical forms of two FASM files")]
+    FasmDiff(FasmDiff),
+    #[clap(about = "combine golden and primary bitstreams into a multi-boot SPI flash image")]
+    FlashImage(FlashImage),
+    #[clap(about = "list the bitstreams inside a SPI flash image")]
+    FlashList(FlashList),
     #[clap(about = "export a BBA file for the nextpnr build")]
     BBAExport(BBAExport),
     #[cfg(feature = "interchange")]
@@ -44,6 +67,20 @@ enum SubCommand {
 struct Pack {
     #[clap(long, about = "create background programmable bitstream (advanced)")]
     background: bool,
+    #[clap(long, parse(try_from_str = parse_u32_arg), about = "raw CTRL0 value, overriding any CTRL0 settings in the FASM (advanced)")]
+    ctrl0: Option<u32>,
+    #[clap(long, about = "compress frame data")]
+    compress: bool,
+    #[clap(long, about = "disable configuration readback")]
+    secure: bool,
+    #[clap(long, about = "omit the metadata header, for raw flash images")]
+    headerless: bool,
+    #[clap(long, about = "bit reverse each byte, for slave SPI shifted out LSB first")]
+    reverse_bits: bool,
+    #[clap(long, parse(try_from_str = parse_u32_arg), about = "USERCODE value, overriding any oxide.usercode in the FASM")]
+    usercode: Option<u32>,
+    #[clap(long, about = "FASM of the current configuration; only write frames that differ from it")]
+    base: Option<String>,
     #[clap(about = "input FASM file")]
     fasm: String,
     #[clap(about = "output bitstream")]
@@ -54,14 +91,37 @@ struct Pack {
 impl Pack {
     pub fn run(&self) -> Result<()> {
         let mut db = Database::new_builtin(DATABASE_DIR);
-        let parsed_fasm = ParsedFasm::parse(&self.fasm).unwrap();
-
-        let mut chip = Chip::from_fasm(&mut db, &parsed_fasm, None);
+        let mut chip = fasm_to_chip(&mut db, &self.fasm, None)?;
 
+        if let Some(ctrl0) = self.ctrl0 {
+            chip.ctrl0 = ConfigControl::from_ctrl0(ctrl0);
+        }
         if self.background {
-            chip.settings.insert("background".to_string(), "1".to_string());
+            chip.ctrl0.background = true;
+        }
+        if self.compress {
+            chip.compress = true;
+        }
+        if self.headerless {
+            chip.headerless = true;
+        }
+        if self.reverse_bits {
+            chip.reverse_bits = true;
         }
-        let bs = BitstreamParser::serialise_chip(&chip);
+        if self.secure {
+            chip.security = SecurityMode::ReadbackDisabled;
+        }
+        if let Some(uc) = self.usercode {
+            chip.usercode = uc;
+        }
+        let bs = match &self.base {
+            Some(base) => {
+                let base_chip = fasm_to_chip(&mut db, base, Some(&chip.device))?;
+                BitstreamParser::serialise_partial(&chip, &base_chip, &mut db)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?
+            }
+            None => BitstreamParser::serialise_chip_masked(&chip, &mut db),
+        };
         let mut outfile = File::create(&self.bitstream).unwrap();
         outfile.write_all(&bs)?;
         Ok(())
@@ -70,6 +130,14 @@ impl Pack {
 
 #[derive(Clap)]
 struct Unpack {
+    #[clap(long, about = "check frame ECC, treating a mismatch as a corrupt bitstream")]
+    strict_ecc: bool,
+    #[clap(long, about = "check the SED CRC, treating a mismatch as a corrupt bitstream (unconfirmed algorithm)")]
+    check_sed_crc: bool,
+    #[clap(long, about = "print bitstream commands to stderr as they are parsed")]
+    verbose: bool,
+    #[clap(long, about = "annotate features with their bel, location and description")]
+    annotate: bool,
     #[clap(about = "input bitstream")]
     bitstream: String,
     #[clap(about = "output FASM file")]
@@ -79,33 +147,450 @@ struct Unpack {
 impl Unpack {
     pub fn run(&self) -> Result<()> {
         let mut db = Database::new_builtin(DATABASE_DIR);
-        let chip = BitstreamParser::parse_file(&mut db, &self.bitstream).unwrap();
+        let mut buffer = Vec::new();
+        File::open(&self.bitstream)?.read_to_end(&mut buffer)?;
+        let mut parser = BitstreamParser::new(&buffer);
+        parser.set_strict_ecc(self.strict_ecc);
+        parser.set_check_sed_crc(self.check_sed_crc);
+        if self.verbose {
+            parser.set_observer(Box::new(|event: &ParseEvent| match event {
+                ParseEvent::Metadata(m) => eprintln!("metadata: {}", m),
+                ParseEvent::Command(dc) => eprintln!("{:08X}: {}", dc.offset, dc.command),
+                _ => {}
+            }));
+        }
+        let mut chip = parser
+            .parse(&mut db)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        chip.cram_to_tiles();
 
         let mut outfile = File::create(&self.fasm)?;
+        chip.write_fasm(&mut db, &mut outfile, self.annotate)?;
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct Patch {
+    #[clap(long, about = "write a partial bitstream containing only the changed frames")]
+    partial: bool,
+    #[clap(about = "input bitstream")]
+    input: String,
+    #[clap(about = "FASM to apply; features set to 0 are cleared")]
+    fasm: String,
+    #[clap(about = "output bitstream")]
+    bitstream: String,
+}
+
+impl Patch {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let base = BitstreamParser::parse_file(&mut db, &self.input)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let parsed_fasm = ParsedFasm::parse(&self.fasm)?;
+        let mut chip = base.clone();
+        chip.try_apply_fasm(&mut db, &parsed_fasm)
+            .map_err(|errors| fasm_errors(&self.fasm, &errors))?;
+        let bs = match self.partial {
+            true => BitstreamParser::serialise_partial(&chip, &base, &mut db)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?,
+            false => BitstreamParser::serialise_chip_masked(&chip, &mut db),
+        };
+        File::create(&self.bitstream)?.write_all(&bs)?;
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct Disasm {
+    #[clap(long, about = "also dump frame (decompressed) and bus write payloads in hex")]
+    data: bool,
+    #[clap(about = "input bitstream")]
+    bitstream: String,
+}
+
+impl Disasm {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let mut buffer = Vec::new();
+        File::open(&self.bitstream)?.read_to_end(&mut buffer)?;
+        let mut parser = BitstreamParser::new(&buffer);
+        let to_io_err = |e: BitstreamError| Error::new(ErrorKind::InvalidData, e.to_string());
+        let mut out = BufWriter::new(stdout());
+
+        let commands: Vec<Result<DecodedCommand>> = parser
+            .commands(&mut db)
+            .map_err(to_io_err)?
+            .map(|x| x.map_err(to_io_err))
+            .collect();
+        for metadata in parser.get_metadata().iter().filter(|m| !m.is_empty()) {
+            writeln!(out, "; metadata: {}", metadata)?;
+        }
+        for dc in commands {
+            let dc = dc?;
+            write!(out, "{:08X}: {}", dc.offset, dc.command)?;
+            let payload_len = dc.command.payload_len();
+            if let BitstreamCommand::ProgIncrCmp { .. } = dc.command {
+                write!(out, " ; {} bytes in bitstream, {} payload bytes decompressed", dc.length, payload_len)?;
+            } else if payload_len > 0 {
+                write!(out, " ; {} payload bytes", payload_len)?;
+            }
+            match dc.crc {
+                CrcStatus::Unchecked => {}
+                CrcStatus::Ok => write!(out, " ; CRC ok")?,
+                CrcStatus::Mismatch {
+                    offset,
+                    expected,
+                    found,
+                } => write!(
+                    out,
+                    " ; CRC MISMATCH at {:08X}: expected 0x{:04X}, found 0x{:04X}",
+                    offset, expected, found
+                )?,
+            }
+            writeln!(out)?;
+            if self.data {
+                match &dc.command {
+                    BitstreamCommand::ProgIncrRti { address, frames, .. }
+                    | BitstreamCommand::ProgIncrCmp { address, frames, .. } => {
+                        // Compressed frames are shown decompressed
+                        for (i, frame) in frames.iter().enumerate() {
+                            writeln!(out, "    F0x{:08X}: {}", address.wrapping_add(i as u32), to_hex(frame))?;
+                        }
+                    }
+                    BitstreamCommand::BusWrite { address, data, .. } => {
+                        for (i, chunk) in data.chunks(32).enumerate() {
+                            writeln!(out, "    0x{:08X}: {}", address + (i as u32) * 32, to_hex(chunk))?;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
 
-        writeln!(outfile, "{{ oxide.device=\"{}\" }}", chip.device)?;
-        writeln!(outfile, "{{ oxide.device_variant=\"{}\" }}", chip.variant)?;
-        writeln!(outfile, "")?;
+#[derive(Clap)]
+struct Svf {
+    #[clap(long, about = "program the SPI flash through the FPGA, rather than SRAM")]
+    flash: bool,
+    #[clap(long, parse(try_from_str = parse_u32_arg), default_value = "0", about = "flash address to program at")]
+    flash_address: u32,
+    #[clap(about = "input FASM file or bitstream")]
+    input: String,
+    #[clap(about = "output SVF file")]
+    svf: String,
+}
 
-        for metadata in chip.metadata.iter() {
-            writeln!(outfile, "{{ oxide.meta=\"{}\" }}", metadata)?;
+impl Svf {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let (chip, bitstream) = if self.input.ends_with(".fasm") {
+            let chip = fasm_to_chip(&mut db, &self.input, None)?;
+            let bitstream = BitstreamParser::serialise_chip_masked(&chip, &mut db);
+            (chip, bitstream)
+        } else {
+            let mut bitstream = Vec::new();
+            File::open(&self.input)?.read_to_end(&mut bitstream)?;
+            let chip = BitstreamParser::parse_bytes(&mut db, &bitstream)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+            (chip, bitstream)
+        };
+        let target = match self.flash {
+            true => SvfTarget::Flash {
+                address: self.flash_address,
+            },
+            false => SvfTarget::Sram,
+        };
+        // Generate the whole SVF first so nothing is written if it fails
+        let mut svf = Vec::new();
+        write_svf(&chip, &bitstream, target, &mut svf)?;
+        File::create(&self.svf)?.write_all(&svf)
+    }
+}
+
+#[derive(Clap)]
+struct Readback {
+    #[clap(long, about = "also write a mask of bits to ignore when comparing readback")]
+    mask: Option<String>,
+    #[clap(about = "input FASM file")]
+    fasm: String,
+    #[clap(about = "output readback image")]
+    readback: String,
+}
+
+impl Readback {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let chip = fasm_to_chip(&mut db, &self.fasm, None)?;
+        File::create(&self.readback)?.write_all(&BitstreamParser::serialise_readback(&chip))?;
+        if let Some(mask) = &self.mask {
+            let masked = chip.ecc_masked_bits(&mut db);
+            File::create(mask)?.write_all(&BitstreamParser::serialise_readback_mask(&chip, &masked))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct Verify {
+    #[clap(about = "expected configuration, as a bitstream or FASM file")]
+    expected: String,
+    #[clap(about = "readback image")]
+    readback: String,
+}
+
+impl Verify {
+    pub fn run(&self) -> Result<ExitCode> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let expected = load_chip(&mut db, &self.expected)?;
+        let readback = BitstreamParser::parse_file(&mut db, &self.readback)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let mismatches = expected
+            .verify_readback(&mut db, &readback)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("readback doesn't match: {}", e)))?;
+        for m in mismatches.iter() {
+            println!("F{}B{}: expected {}, read {}", m.frame, m.bit, m.expected as u8, !m.expected as u8);
+            for t in m.tiles.iter() {
+                println!("    {} F{}B{}: {}", t.tile, t.frame, t.bit, describe_features(&t.features));
+            }
         }
-        if !chip.metadata.is_empty() {
-            writeln!(outfile, "")?;
+        println!("{} mismatching bits", mismatches.len());
+        Ok(if !mismatches.is_empty() { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+    }
+}
+
+#[derive(Clap)]
+struct Diff {
+    #[clap(about = "old configuration, as a bitstream or FASM file")]
+    old: String,
+    #[clap(about = "new configuration, as a bitstream or FASM file")]
+    new: String,
+}
+
+impl Diff {
+    pub fn run(&self) -> Result<ExitCode> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let old = load_chip(&mut db, &self.old)?;
+        let new = load_chip(&mut db, &self.new)?;
+        let diff = prjoxide::diff::diff_chips(&mut db, &old, &new)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("cannot compare: {}", e)))?;
+        diff.print(&mut stdout())?;
+        Ok(if !diff.is_empty() { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+    }
+}
+
+#[derive(Clap)]
+struct FasmDiff {
+    #[clap(about = "old FASM file")]
+    old: String,
+    #[clap(about = "new FASM file")]
+    new: String,
+}
+
+impl FasmDiff {
+    pub fn run(&self) -> Result<ExitCode> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let old = canonical_fasm_lines(&mut db, &self.old)?;
+        let new = canonical_fasm_lines(&mut db, &self.new)?;
+        let mut differs = false;
+        for line in old.union(&new) {
+            match (old.contains(line), new.contains(line)) {
+                (true, false) => println!("- {}", line),
+                (false, true) => println!("+ {}", line),
+                _ => continue,
+            }
+            differs = true;
         }
+        Ok(if differs { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+    }
+}
+
+#[derive(Clap)]
+struct FlashImage {
+    #[clap(long, about = "golden bitstream (not yet supported)")]
+    golden: Option<String>,
+    #[clap(long, parse(try_from_str = parse_u32_arg), about = "flash address of the golden bitstream")]
+    golden_address: Option<u32>,
+    #[clap(long, required = true, number_of_values = 1, about = "primary bitstream as ADDRESS:FILE, jumped to at boot (only one is supported)")]
+    primary: Vec<String>,
+    #[clap(long, default_value = "fast", about = "SPI read mode after the jump: standard, fast, dual or quad")]
+    spi_mode: String,
+    #[clap(long, about = "write Intel HEX rather than raw binary")]
+    hex: bool,
+    #[clap(about = "output flash image")]
+    image: String,
+}
 
-        for tile in chip.tiles {
-            tile.write_fasm(&mut db, &mut outfile);
+impl FlashImage {
+    pub fn run(&self) -> Result<()> {
+        let read_part = |address: u32, filename: &str| -> Result<FlashImagePart> {
+            let mut data = Vec::new();
+            File::open(filename)?.read_to_end(&mut data)?;
+            Ok(FlashImagePart { address, data })
+        };
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+        let mode = SpiReadMode::from_name(&self.spi_mode)
+            .ok_or_else(|| invalid(format!("invalid SPI mode {}", self.spi_mode)))?;
+        let golden = match (&self.golden, self.golden_address) {
+            (Some(golden), Some(address)) => Some(read_part(address, golden)?),
+            (None, None) => None,
+            _ => return Err(invalid("--golden and --golden-address must be given together".to_string())),
+        };
+        let mut primaries = Vec::new();
+        for p in self.primary.iter() {
+            let mut split = p.splitn(2, ':');
+            let address = split.next().and_then(parse_u32_value);
+            match (address, split.next()) {
+                (Some(address), Some(filename)) => primaries.push(read_part(address, filename)?),
+                _ => return Err(invalid(format!("invalid primary image {}, expected ADDRESS:FILE", p))),
+            }
+        }
+        let image = build_flash_image(golden.as_ref(), &primaries, mode).map_err(|e| invalid(e.to_string()))?;
+        let mut outfile = File::create(&self.image)?;
+        if self.hex {
+            write_intel_hex(&image, &mut BufWriter::new(outfile))?;
+        } else {
+            outfile.write_all(&image)?;
         }
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct FlashList {
+    #[clap(about = "input flash image, raw binary or Intel HEX")]
+    image: String,
+}
 
-        for (addr, val) in chip.ipconfig.iter() {
-            writeln!(outfile, "IP_UNKNOWN.0x{:08X}[7:0] = 8'h{:02X};", addr, val)?;
+impl FlashList {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let mut buffer = Vec::new();
+        File::open(&self.image)?.read_to_end(&mut buffer)?;
+        let to_io_err = |e: FlashImageError| Error::new(ErrorKind::InvalidData, e.to_string());
+        if buffer.first() == Some(&b':') {
+            buffer = read_intel_hex(&String::from_utf8_lossy(&buffer)).map_err(to_io_err)?;
         }
+        for entry in list_flash_images(&mut db, &buffer).map_err(to_io_err)? {
+            write!(stdout(), "{:08X}: {:8} bytes ", entry.offset, entry.length)?;
+            match entry.content {
+                FlashImageContent::Jump { mode, address } => {
+                    let mode = match mode {
+                        Some(m) => SpiReadMode::from_opcode(m)
+                            .map(|m| m.name().to_string())
+                            .unwrap_or_else(|| format!("0x{:02X}", m)),
+                        None => "default".to_string(),
+                    };
+                    println!("jump to 0x{:08X} ({} read)", address, mode);
+                }
+                FlashImageContent::Bitstream { idcode, metadata } => {
+                    match idcode {
+                        Some(i) => println!("bitstream, IDCODE 0x{:08X}", i),
+                        None => println!("bitstream"),
+                    }
+                    for m in metadata {
+                        println!("    {}", m);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+struct Explain {
+    #[clap(long, about = "FRAME is a frame address, as used by LSC_WRITE_ADDRESS, rather than a frame index")]
+    address: bool,
+    #[clap(about = "device name")]
+    device: String,
+    #[clap(parse(try_from_str = parse_u32_arg), about = "frame")]
+    frame: u32,
+    #[clap(parse(try_from_str = parse_u32_arg), about = "bit within the frame")]
+    bit: u32,
+}
 
+impl Explain {
+    pub fn run(&self) -> Result<()> {
+        let mut db = Database::new_builtin(DATABASE_DIR);
+        let chip = Chip::from_name(&mut db, &self.device);
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+        let frame = if self.address {
+            chip.try_frame_addr_to_idx(self.frame)
+                .ok_or_else(|| invalid(format!("invalid frame address 0x{:08X}", self.frame)))?
+        } else {
+            self.frame as usize
+        };
+        let bit = self.bit as usize;
+        if frame >= chip.data.frames || bit >= chip.data.bits_per_frame {
+            return Err(invalid(format!(
+                "F{}B{} is outside the {} frames of {} bits",
+                frame, bit, chip.data.frames, chip.data.bits_per_frame
+            )));
+        }
+        println!("F{}B{}", frame, bit);
+        let tiles = chip.explain_bit(&mut db, frame, bit);
+        if tiles.is_empty() {
+            println!("    not in any tile");
+        }
+        for t in tiles.iter() {
+            let tile = chip
+                .tile_by_name(&t.tile)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", e, t.tile)))?;
+            println!("    {} ({}, x={} y={}) F{}B{}: {}", t.tile, t.tiletype, tile.x, tile.y, t.frame, t.bit,
+                describe_features(&t.features));
+        }
         Ok(())
     }
 }
 
+// Load a configuration from a bitstream, or from FASM if the name ends in .fasm
+fn load_chip(db: &mut Database, filename: &str) -> Result<Chip> {
+    if filename.ends_with(".fasm") {
+        fasm_to_chip(db, filename, None)
+    } else {
+        BitstreamParser::parse_file(db, filename)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+// Load a configuration from FASM, printing every problem found to stderr
+fn fasm_to_chip(db: &mut Database, filename: &str, device: Option<&str>) -> Result<Chip> {
+    let parsed_fasm = ParsedFasm::parse(filename)?;
+    Chip::try_from_fasm(db, &parsed_fasm, device).map_err(|errors| fasm_errors(filename, &errors))
+}
+
+// Features and attributes of the canonical form of a FASM file
+fn canonical_fasm_lines(db: &mut Database, filename: &str) -> Result<std::collections::BTreeSet<String>> {
+    let parsed_fasm = ParsedFasm::parse(filename)?;
+    let canonical = canonicalise_fasm(db, &parsed_fasm, None).map_err(|errors| fasm_errors(filename, &errors))?;
+    Ok(canonical.lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect())
+}
+
+fn fasm_errors(filename: &str, errors: &[FasmConfigError]) -> Error {
+    for e in errors.iter() {
+        eprintln!("{}: {}", filename, e);
+    }
+    Error::new(ErrorKind::InvalidData, format!("{} errors in {}", errors.len(), filename))
+}
+
+fn describe_features(features: &[BitFeature]) -> String {
+    match features.len() {
+        0 => "unknown".to_string(),
+        _ => features.iter().map(|f| f.to_string()).collect::<Vec<String>>().join(", "),
+    }
+}
+
+fn parse_u32_arg(s: &str) -> std::result::Result<u32, String> {
+    parse_u32_value(s).ok_or(format!("invalid value {}", s))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
 #[derive(Clap)]
 struct BBAExport {
     #[clap(about = "device family name")]
@@ -203,22 +688,63 @@ impl InterchangeExport {
     }
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let opts: Opts = Opts::parse();
-    match opts.subcmd {
+    let status = match opts.subcmd {
         SubCommand::Pack(t) => {
-            t.run()
+            t.run()?;
+            ExitCode::SUCCESS
         }
         SubCommand::Unpack(t) => {
-            t.run()
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Patch(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Disasm(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Svf(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Readback(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Explain(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::Verify(t) => {
+            t.run()?
+        }
+        SubCommand::Diff(t) => {
+            t.run()?
+        }
+        SubCommand::FasmDiff(t) => {
+            t.run()?
+        }
+        SubCommand::FlashImage(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
+        }
+        SubCommand::FlashList(t) => {
+            t.run()?;
+            ExitCode::SUCCESS
         }
         SubCommand::BBAExport(t) => {
-            t.run()
+            t.run()?;
+            ExitCode::SUCCESS
         }
         #[cfg(feature = "interchange")]
         SubCommand::InterchangeExport(t) => {
-            t.run()
+            t.run()?;
+            ExitCode::SUCCESS
         }
-    }
-
+    };
+    Ok(status)
 }
@@ -1,9 +1,13 @@
 use crate::chip::*;
 use crate::database::*;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
+
+use log::*;
 
 pub struct BitstreamParser {
     data: Vec<u8>,
@@ -12,6 +16,424 @@ pub struct BitstreamParser {
     ecc14: u16,
     verbose: bool,
     metadata: Vec<String>,
+    // Command currently being decoded, for error reporting
+    curr_cmd: Option<u8>,
+    // Decoder state
+    curr_chip: Option<Chip>,
+    curr_frame: u32,
+    bus_addr: u32,
+    // Dictionary for compressed frame data
+    comp_dict: Option<[u8; 8]>,
+    // Check frame ECC, taking LUT RAM masking into account
+    strict_ecc: bool,
+    // Check the SED CRC against the frames loaded
+    check_sed_crc: bool,
+    // Input had each byte bit reversed
+    bit_reversed: bool,
+    // (offset, frame address, ECC) of each loaded frame, for strict ECC checking
+    frame_ecc: Vec<(usize, u32, u16)>,
+    // Offset of each frame of the last decoded frame write command
+    frame_offsets: Vec<usize>,
+    // CRAM bits left out of the frame ECC when writing (see Chip::ecc_masked_bits)
+    ecc_mask: BTreeSet<(usize, usize)>,
+    // Receives parse events
+    observer: Option<Box<dyn ParseObserver>>,
+}
+
+// Events reported to a ParseObserver while parsing a bitstream
+#[derive(Debug)]
+pub enum ParseEvent<'a> {
+    // Input was bit reversed, and has been corrected
+    BitReversed,
+    // Preamble found, commands start at offset
+    BitstreamStart { offset: usize },
+    Metadata(&'a str),
+    // Command decoded; reported before it is applied to the chip
+    Command(&'a DecodedCommand),
+    // Frame loaded into CRAM frame `index`, with the ECC from the bitstream
+    // and the ECC computed from the frame data (without LUT RAM masking)
+    Frame {
+        address: u32,
+        index: usize,
+        ecc: u16,
+        computed_ecc: u16,
+    },
+}
+
+// Optional observer for parse events, set with BitstreamParser::set_observer
+pub trait ParseObserver {
+    fn event(&mut self, event: &ParseEvent);
+}
+
+impl<F: FnMut(&ParseEvent)> ParseObserver for F {
+    fn event(&mut self, event: &ParseEvent) {
+        self(event)
+    }
+}
+
+// Errors that can occur while parsing a bitstream
+// offset is the byte offset into the input at which the problem was found
+// command is the opcode being decoded at the time, if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitstreamError {
+    // Failed to open or read the input file
+    Io(String),
+    // No bitstream preamble found
+    NoPreamble,
+    // Input ended part way through a command
+    UnexpectedEof {
+        offset: usize,
+        command: Option<u8>,
+    },
+    // Opcode not known to the parser
+    UnknownCommand {
+        offset: usize,
+        command: u8,
+    },
+    // CRC16 in the bitstream doesn't match the computed one
+    CrcMismatch {
+        offset: usize,
+        command: Option<u8>,
+        expected: u16,
+        found: u16,
+    },
+    // A fixed field (frame trailer, padding, etc) has an unexpected value
+    UnexpectedValue {
+        offset: usize,
+        command: Option<u8>,
+        field: &'static str,
+        expected: u32,
+        found: u32,
+    },
+    // IDCODE doesn't match any device in the database
+    UnknownIdcode {
+        offset: usize,
+        command: Option<u8>,
+        idcode: u32,
+    },
+    // Command needs the device to be known, but VERIFY_ID hasn't been seen yet
+    NoDevice {
+        offset: usize,
+        command: Option<u8>,
+    },
+    // Compressed frames without a preceding LSC_WRITE_COMP_DIC
+    NoDictionary {
+        offset: usize,
+        command: Option<u8>,
+    },
+    // Bus address outside of any known IP region
+    InvalidBusAddress {
+        offset: usize,
+        command: Option<u8>,
+        address: u32,
+    },
+    // Frame ECC in the bitstream doesn't match the frame data (strict mode only)
+    EccMismatch {
+        offset: usize,
+        address: u32,
+        expected: u16,
+        found: u16,
+    },
+    // SED CRC in the bitstream doesn't match the one computed from the frames
+    // (only checked if enabled)
+    SedCrcMismatch {
+        offset: usize,
+        expected: u32,
+        found: u32,
+    },
+    // Bitstream ended without any device configuration
+    NoContent,
+    // Input is valid but can't be handled by the requested operation
+    UnsupportedFormat(&'static str),
+}
+
+impl fmt::Display for BitstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Human readable context
+        let ctx = |offset: &usize, command: &Option<u8>| match command {
+            Some(c) => format!("in {} at offset 0x{:08X}", command_name(*c), offset),
+            None => format!("at offset 0x{:08X}", offset),
+        };
+        match self {
+            BitstreamError::Io(msg) => write!(f, "I/O error: {}", msg),
+            BitstreamError::NoPreamble => write!(f, "failed to find preamble"),
+            BitstreamError::UnexpectedEof { offset, command } => {
+                write!(f, "unexpected end of bitstream {}", ctx(offset, command))
+            }
+            BitstreamError::UnknownCommand { offset, command } => write!(
+                f,
+                "unknown bitstream command 0x{:02X} at offset 0x{:08X}",
+                command, offset
+            ),
+            BitstreamError::CrcMismatch {
+                offset,
+                command,
+                expected,
+                found,
+            } => write!(
+                f,
+                "CRC mismatch {}: expected 0x{:04X}, found 0x{:04X}",
+                ctx(offset, command),
+                expected,
+                found
+            ),
+            BitstreamError::UnexpectedValue {
+                offset,
+                command,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "unexpected {} {}: expected 0x{:X}, found 0x{:X}",
+                field,
+                ctx(offset, command),
+                expected,
+                found
+            ),
+            BitstreamError::UnknownIdcode {
+                offset,
+                command,
+                idcode,
+            } => write!(
+                f,
+                "no device in database with IDCODE 0x{:08X} ({})",
+                idcode,
+                ctx(offset, command)
+            ),
+            BitstreamError::NoDevice { offset, command } => {
+                write!(f, "got bitstream before IDCODE {}", ctx(offset, command))
+            }
+            BitstreamError::NoDictionary { offset, command } => write!(
+                f,
+                "got compressed frames before dictionary {}",
+                ctx(offset, command)
+            ),
+            BitstreamError::InvalidBusAddress {
+                offset,
+                command,
+                address,
+            } => write!(
+                f,
+                "invalid bus address 0x{:08X} {}",
+                address,
+                ctx(offset, command)
+            ),
+            BitstreamError::EccMismatch {
+                offset,
+                address,
+                expected,
+                found,
+            } => write!(
+                f,
+                "ECC mismatch for frame 0x{:08X} at offset 0x{:08X}: expected 0x{:04X}, found 0x{:04X}",
+                address, offset, expected, found
+            ),
+            BitstreamError::SedCrcMismatch {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "SED CRC mismatch {}: expected 0x{:08X}, found 0x{:08X}",
+                ctx(offset, &Some(LSC_PROG_SED_CRC)),
+                expected,
+                found
+            ),
+            BitstreamError::NoContent => write!(f, "missing bitstream content"),
+            BitstreamError::UnsupportedFormat(msg) => write!(f, "unsupported bitstream: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for BitstreamError {}
+
+// CRC status of a decoded command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcStatus {
+    // Command doesn't carry a CRC
+    Unchecked,
+    // All CRCs carried by the command matched
+    Ok,
+    // First CRC in the command that didn't match
+    Mismatch {
+        offset: usize,
+        expected: u16,
+        found: u16,
+    },
+}
+
+// A decoded bitstream command with its operands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitstreamCommand {
+    // Run of DUMMY (0xFF) bytes
+    Padding { count: usize },
+    // SPI flash read opcode to use after a jump
+    SpiMode { mode: u8 },
+    // Continue configuration from another flash address
+    Jump { address: u32 },
+    ResetCrc,
+    VerifyId { idcode: u32 },
+    ProgCntrl0 { ctrl0: u32 },
+    WriteCompDic { flags: u8, dict: [u8; 8] },
+    InitAddress,
+    WriteAddress { address: u32 },
+    // Frame data, written starting at frame address `address`
+    // Each frame is stored as in the bitstream, including ECC and padding bits
+    ProgIncrRti {
+        config: u8,
+        address: u32,
+        frames: Vec<Vec<u8>>,
+    },
+    // As above, but compressed in the bitstream; frames are stored decompressed
+    ProgIncrCmp {
+        config: u8,
+        address: u32,
+        frames: Vec<Vec<u8>>,
+    },
+    PowerCtrl { value: u8 },
+    ProgramUsercode { flags: u8, usercode: u32 },
+    ProgramSecurity { flags: u8 },
+    ProgSedCrc { crc: u32 },
+    BusAddress { address: u32 },
+    // IP configuration data, written starting at bus address `address`
+    BusWrite {
+        config: u8,
+        address: u32,
+        frame_count: usize,
+        data: Vec<u8>,
+    },
+    ProgramDone,
+}
+
+impl BitstreamCommand {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            BitstreamCommand::Padding { .. } => DUMMY,
+            BitstreamCommand::ResetCrc => LSC_RESET_CRC,
+            BitstreamCommand::VerifyId { .. } => VERIFY_ID,
+            BitstreamCommand::ProgCntrl0 { .. } => LSC_PROG_CNTRL0,
+            BitstreamCommand::WriteCompDic { .. } => LSC_WRITE_COMP_DIC,
+            BitstreamCommand::InitAddress => LSC_INIT_ADDRESS,
+            BitstreamCommand::WriteAddress { .. } => LSC_WRITE_ADDRESS,
+            BitstreamCommand::ProgIncrRti { .. } => LSC_PROG_INCR_RTI,
+            BitstreamCommand::ProgIncrCmp { .. } => LSC_PROG_INCR_CMP,
+            BitstreamCommand::PowerCtrl { .. } => LSC_POWER_CTRL,
+            BitstreamCommand::ProgramUsercode { .. } => ISC_PROGRAM_USERCODE,
+            BitstreamCommand::ProgramSecurity { .. } => ISC_PROGRAM_SECURITY,
+            BitstreamCommand::ProgSedCrc { .. } => LSC_PROG_SED_CRC,
+            BitstreamCommand::BusAddress { .. } => LSC_BUS_ADDRESS,
+            BitstreamCommand::BusWrite { .. } => LSC_BUS_WRITE,
+            BitstreamCommand::ProgramDone => ISC_PROGRAM_DONE,
+            BitstreamCommand::SpiMode { .. } => SPI_MODE,
+            BitstreamCommand::Jump { .. } => JUMP,
+        }
+    }
+    pub fn mnemonic(&self) -> &'static str {
+        command_name(self.opcode())
+    }
+    // Length in bytes of the frame or bus data carried by the command; for
+    // compressed frames this is the length after decompression
+    pub fn payload_len(&self) -> usize {
+        match self {
+            BitstreamCommand::ProgIncrRti { frames, .. }
+            | BitstreamCommand::ProgIncrCmp { frames, .. } => frames.iter().map(|f| f.len()).sum(),
+            BitstreamCommand::BusWrite { data, .. } => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl fmt::Display for BitstreamCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let operands = match self {
+            BitstreamCommand::Padding { count } => format!("count={}", count),
+            BitstreamCommand::VerifyId { idcode } => format!("idcode=0x{:08X}", idcode),
+            BitstreamCommand::ProgCntrl0 { ctrl0 } => format!("ctrl0=0x{:08X}", ctrl0),
+            BitstreamCommand::SpiMode { mode } => format!("mode=0x{:02X}", mode),
+            BitstreamCommand::Jump { address } => format!("address=0x{:08X}", address),
+            BitstreamCommand::WriteCompDic { flags, dict } => format!(
+                "flags=0x{:02X} dict={}",
+                flags,
+                dict.iter().map(|d| format!("{:02X}", d)).collect::<Vec<String>>().join(",")
+            ),
+            BitstreamCommand::WriteAddress { address } => format!("address=0x{:08X}", address),
+            BitstreamCommand::ProgIncrRti {
+                config,
+                address,
+                frames,
+            }
+            | BitstreamCommand::ProgIncrCmp {
+                config,
+                address,
+                frames,
+            } => format!(
+                "config=0x{:02X} frames={} address=0x{:08X}",
+                config,
+                frames.len(),
+                address
+            ),
+            BitstreamCommand::PowerCtrl { value } => format!("value=0x{:02X}", value),
+            BitstreamCommand::ProgramUsercode { flags, usercode } => {
+                format!("flags=0x{:02X} usercode=0x{:08X}", flags, usercode)
+            }
+            BitstreamCommand::ProgramSecurity { flags } => format!("flags=0x{:02X}", flags),
+            BitstreamCommand::ProgSedCrc { crc } => format!("crc=0x{:08X}", crc),
+            BitstreamCommand::BusAddress { address } => format!("address=0x{:08X}", address),
+            BitstreamCommand::BusWrite {
+                config,
+                address,
+                frame_count,
+                ..
+            } => format!(
+                "config=0x{:02X} frames={} address=0x{:08X}",
+                config, frame_count, address
+            ),
+            BitstreamCommand::ResetCrc
+            | BitstreamCommand::InitAddress
+            | BitstreamCommand::ProgramDone => String::new(),
+        };
+        if operands.is_empty() {
+            write!(f, "{}", self.mnemonic())
+        } else {
+            write!(f, "{:<20} {}", self.mnemonic(), operands)
+        }
+    }
+}
+
+// A command along with its location in the bitstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedCommand {
+    // Byte offset of the opcode
+    pub offset: usize,
+    // Total length in bytes, including operands, payload and CRCs
+    pub length: usize,
+    pub command: BitstreamCommand,
+    pub crc: CrcStatus,
+}
+
+// Iterator over the commands of a bitstream, see BitstreamParser::commands
+pub struct BitstreamCommands<'a> {
+    parser: &'a mut BitstreamParser,
+    db: &'a mut Database,
+    failed: bool,
+}
+
+impl<'a> Iterator for BitstreamCommands<'a> {
+    type Item = Result<DecodedCommand, BitstreamError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.parser.decode_command(self.db) {
+            Ok(cmd) => cmd.map(Ok),
+            Err(e) => {
+                // Can't resynchronise after an error
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
 
 // Magic sequences
@@ -20,31 +442,29 @@ const COMMENT_END: [u8; 2] = [0x00, 0xFF];
 const COMMENT_END_RDBK: [u8; 2] = [0x00, 0xFE];
 const PREAMBLE: [u8; 4] = [0xFF, 0xFF, 0xBD, 0xB3];
 
+fn reverse_bytes(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().map(|b| b.reverse_bits()).collect()
+}
+
 // Commands
 
-#[allow(dead_code)]
 const SPI_MODE: u8 = 0b01111001;
 
-#[allow(dead_code)]
 const JUMP: u8 = 0b01111110;
 
 const LSC_RESET_CRC: u8 = 0b00111011;
 const VERIFY_ID: u8 = 0b11100010;
 
-#[allow(dead_code)]
 const LSC_WRITE_COMP_DIC: u8 = 0b00000010;
 const LSC_PROG_CNTRL0: u8 = 0b00100010;
 const LSC_INIT_ADDRESS: u8 = 0b01000110;
 const LSC_WRITE_ADDRESS: u8 = 0b10110100;
 
-#[allow(dead_code)]
 const LSC_PROG_INCR_CMP: u8 = 0b10111000;
 const LSC_PROG_INCR_RTI: u8 = 0b10000010;
 
-#[allow(dead_code)]
 const LSC_PROG_SED_CRC: u8 = 0b10100010;
 
-#[allow(dead_code)]
 const ISC_PROGRAM_SECURITY: u8 = 0b11001110;
 const ISC_PROGRAM_USERCODE: u8 = 0b11000010;
 const LSC_BUS_ADDRESS: u8 = 0b11110110;
@@ -66,6 +486,31 @@ enum BitstreamType {
     READBACK
 }
 
+// Get the mnemonic for a command opcode
+fn command_name(cmd: u8) -> &'static str {
+    match cmd {
+        SPI_MODE => "SPI_MODE",
+        JUMP => "JUMP",
+        LSC_RESET_CRC => "LSC_RESET_CRC",
+        VERIFY_ID => "VERIFY_ID",
+        LSC_WRITE_COMP_DIC => "LSC_WRITE_COMP_DIC",
+        LSC_PROG_CNTRL0 => "LSC_PROG_CNTRL0",
+        LSC_INIT_ADDRESS => "LSC_INIT_ADDRESS",
+        LSC_WRITE_ADDRESS => "LSC_WRITE_ADDRESS",
+        LSC_PROG_INCR_CMP => "LSC_PROG_INCR_CMP",
+        LSC_PROG_INCR_RTI => "LSC_PROG_INCR_RTI",
+        LSC_PROG_SED_CRC => "LSC_PROG_SED_CRC",
+        ISC_PROGRAM_SECURITY => "ISC_PROGRAM_SECURITY",
+        ISC_PROGRAM_USERCODE => "ISC_PROGRAM_USERCODE",
+        LSC_BUS_ADDRESS => "LSC_BUS_ADDRESS",
+        LSC_BUS_WRITE => "LSC_BUS_WRITE",
+        ISC_PROGRAM_DONE => "ISC_PROGRAM_DONE",
+        LSC_POWER_CTRL => "LSC_POWER_CTRL",
+        DUMMY => "DUMMY",
+        _ => "unknown command",
+    }
+}
+
 impl BitstreamParser {
     pub fn new(bitstream: &[u8]) -> BitstreamParser {
         BitstreamParser {
@@ -75,64 +520,104 @@ impl BitstreamParser {
             ecc14: ECC_INIT,
             verbose: false,
             metadata: Vec::new(),
+            curr_cmd: None,
+            curr_chip: None,
+            curr_frame: 0,
+            bus_addr: 0,
+            comp_dict: None,
+            strict_ecc: false,
+            check_sed_crc: false,
+            bit_reversed: false,
+            observer: None,
+            frame_ecc: Vec::new(),
+            frame_offsets: Vec::new(),
+            ecc_mask: BTreeSet::new(),
         }
     }
 
-    pub fn parse_file(db: &mut Database, filename: &str) -> Result<Chip, &'static str> {
-        let mut f = File::open(filename).map_err(|_x| "failed to open file")?;
+    // Log every set bit and frame ECC as frames are loaded (at trace level)
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    // Set an observer to receive parse events
+    pub fn set_observer(&mut self, observer: Box<dyn ParseObserver>) {
+        self.observer = Some(observer);
+    }
+
+    // Remove and return the observer
+    pub fn take_observer(&mut self) -> Option<Box<dyn ParseObserver>> {
+        self.observer.take()
+    }
+
+    fn emit(&mut self, event: &ParseEvent) {
+        if let Some(o) = self.observer.as_mut() {
+            o.event(event);
+        }
+    }
+
+    // Enable checking of every frame's ECC against the frame data once the whole
+    // bitstream is loaded; a mismatch is reported as an error
+    pub fn set_strict_ecc(&mut self, strict_ecc: bool) {
+        self.strict_ecc = strict_ecc;
+    }
+
+    // Enable checking of the SED CRC against the frames loaded before it; a
+    // mismatch is reported as an error. The CRC algorithm (see
+    // Chip::compute_sed_crc) hasn't been confirmed against Radiant output,
+    // so this is off by default
+    pub fn set_check_sed_crc(&mut self, check_sed_crc: bool) {
+        self.check_sed_crc = check_sed_crc;
+    }
+
+    pub fn parse_file(db: &mut Database, filename: &str) -> Result<Chip, BitstreamError> {
+        let mut f = File::open(filename)
+            .map_err(|x| BitstreamError::Io(format!("failed to open {}: {}", filename, x)))?;
         let mut buffer = Vec::new();
         // read the whole file
         f.read_to_end(&mut buffer)
-            .map_err(|_x| "failed to read file")?;
-        let mut parser = BitstreamParser::new(&buffer);
+            .map_err(|x| BitstreamError::Io(format!("failed to read {}: {}", filename, x)))?;
+        BitstreamParser::parse_bytes(db, &buffer)
+    }
+
+    // Parse a bitstream held in memory
+    pub fn parse_bytes(db: &mut Database, data: &[u8]) -> Result<Chip, BitstreamError> {
+        let mut parser = BitstreamParser::new(data);
         let mut c = parser.parse(db)?;
         c.cram_to_tiles();
         Ok(c)
     }
 
+    // Parse a bitstream from a reader, which is read to the end
+    pub fn parse_reader(db: &mut Database, reader: &mut dyn Read) -> Result<Chip, BitstreamError> {
+        let mut buffer = Vec::new();
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(|x| BitstreamError::Io(format!("failed to read bitstream: {}", x)))?;
+        BitstreamParser::parse_bytes(db, &buffer)
+    }
+
+    // Serialise a chip, writing the bitstream to a writer
+    pub fn serialise_to_writer(ch: &Chip, writer: &mut dyn Write) -> std::io::Result<()> {
+        writer.write_all(&BitstreamParser::serialise_chip(ch))
+    }
+
+    // Serialise a chip, with the frame ECC covering every bit. This is only as
+    // Radiant does if no slices are used as LUT RAM, see serialise_chip_masked
     pub fn serialise_chip(ch: &Chip) -> Vec<u8> {
-        let mut b = BitstreamParser {
-            data: Vec::new(),
-            index: 0,
-            crc16: CRC16_INIT,
-            ecc14: ECC_INIT,
-            verbose: false,
-            metadata: Vec::new(),
-        };
-        b.write_string("LSCC"); // magic
-        b.write_bytes(&COMMENT_START); // metadata start
-        for (i, m) in ch.metadata.iter().enumerate() {
-            b.write_string(m);
-            if i < (ch.metadata.len() - 1) {
-                b.write_byte(0x00); // terminator
-            }
-        }
-        b.write_bytes(&COMMENT_END); // metadata end
-        b.write_bytes(&PREAMBLE); // actual bitstream preamble
-        b.write_padding(20);
-        // Reset CRC, twice for some reason
-        b.write_byte(LSC_RESET_CRC);
-        b.write_zeros(3);
-        b.crc16 = CRC16_INIT;
-        b.write_padding(4);
-        b.write_byte(LSC_RESET_CRC);
-        b.write_zeros(3);
-        b.crc16 = CRC16_INIT;
-        b.write_padding(4);
-        // IDCODE check
-        b.write_byte(VERIFY_ID);
-        b.write_zeros(3);
-        b.write_u32(ch.get_idcode());
-        // Set CTRL0
-        let mut ctrl0 = 0x00000000;
-        for (k, v) in ch.settings.iter() {
-            if k == "background" && v == "1" {
-                ctrl0 |= 0x27800000;
-            }
-        }
-        b.write_byte(LSC_PROG_CNTRL0);
-        b.write_zeros(3);
-        b.write_u32(ctrl0);
+        BitstreamParser::serialise_chip_with_mask(ch, BTreeSet::new())
+    }
+
+    // Serialise a chip, leaving bits that change at runtime out of the frame ECC
+    // (see Chip::ecc_masked_bits)
+    pub fn serialise_chip_masked(ch: &Chip, db: &mut Database) -> Vec<u8> {
+        BitstreamParser::serialise_chip_with_mask(ch, ch.ecc_masked_bits(db))
+    }
+
+    fn serialise_chip_with_mask(ch: &Chip, ecc_mask: BTreeSet<(usize, usize)>) -> Vec<u8> {
+        let mut b = BitstreamParser::new(&[]);
+        b.ecc_mask = ecc_mask;
+        b.write_header(ch);
         // Write "IO" frames
         b.write_frame_addr(0x8000);
         b.write_frames(ch, 0x8000, 32);
@@ -146,23 +631,152 @@ impl BitstreamParser {
         b.write_frame_addr(0x8020);
         b.write_frames(ch, 0x8020, ch.tap_frame_count);
         b.write_padding(17);
+        b.write_footer(ch, &ch.ipconfig);
+        return b.finish(ch);
+    }
+
+    // Serialise a partial bitstream, that only writes the frames and IP config
+    // that differ between `ch` and `base`, for reconfiguring a device already
+    // configured with `base`
+    pub fn serialise_partial(ch: &Chip, base: &Chip, db: &mut Database) -> Result<Vec<u8>, DeviceMismatch> {
+        DeviceMismatch::check(ch, base)?;
+        let mut b = BitstreamParser::new(&[]);
+        b.ecc_mask = ch.ecc_masked_bits(db);
+        b.write_header(ch);
+        // Write runs of consecutive changed frames
+        let changed_frames: BTreeSet<usize> = ch.cram.delta(&base.cram).iter().map(|&(f, _b, _v)| f).collect();
+        let mut runs: Vec<(u32, usize)> = Vec::new();
+        for addr in BitstreamParser::frame_addresses(ch).filter(|&a| changed_frames.contains(&ch.frame_addr_to_idx(a))) {
+            match runs.last_mut() {
+                Some((start, count)) if *start + (*count as u32) == addr => *count += 1,
+                _ => runs.push((addr, 1)),
+            }
+        }
+        for (start, count) in runs {
+            b.write_frame_addr(start);
+            b.write_frames(ch, start, count);
+            b.write_padding(17);
+        }
+        // Bus writes are padded to whole bus frames, so rewrite whole frames around
+        // changes. Frames are counted from the base address of each IP
+        let mut ipconfig = BTreeMap::new();
+        let ip_addrs: BTreeSet<u32> = ch.ipconfig.keys().chain(base.ipconfig.keys()).cloned().collect();
+        for addr in ip_addrs
+            .into_iter()
+            .filter(|a| ch.ipconfig.get(a).unwrap_or(&0x00) != base.ipconfig.get(a).unwrap_or(&0x00))
+        {
+            let frame_size = ch.get_bus_frame_size(addr) as u32;
+            let ip_base = ch.ip_at_address(db, addr).map_or(0, |(_ip, ip_base)| ip_base);
+            let frame_start = addr - ((addr - ip_base) % frame_size);
+            for a in frame_start..(frame_start + frame_size) {
+                ipconfig.insert(a, *ch.ipconfig.get(&a).unwrap_or(&0x00));
+            }
+        }
+        b.write_footer(ch, &ipconfig);
+        Ok(b.finish(ch))
+    }
+
+    // Write container, metadata and commands up to the first frame
+    fn write_header(&mut self, ch: &Chip) {
+        if !ch.headerless {
+            self.write_string("LSCC"); // magic
+            self.write_bytes(&COMMENT_START); // metadata start
+            for (i, m) in ch.metadata.iter().enumerate() {
+                self.write_string(m);
+                if i < (ch.metadata.len() - 1) {
+                    self.write_byte(0x00); // terminator
+                }
+            }
+            self.write_bytes(&COMMENT_END); // metadata end
+        }
+        self.write_bytes(&PREAMBLE); // actual bitstream preamble
+        self.write_padding(20);
+        // Reset CRC, twice for some reason
+        self.write_byte(LSC_RESET_CRC);
+        self.write_zeros(3);
+        self.crc16 = CRC16_INIT;
+        self.write_padding(4);
+        self.write_byte(LSC_RESET_CRC);
+        self.write_zeros(3);
+        self.crc16 = CRC16_INIT;
+        self.write_padding(4);
+        // IDCODE check
+        self.write_byte(VERIFY_ID);
+        self.write_zeros(3);
+        self.write_u32(ch.get_idcode());
+        // Set CTRL0
+        self.write_byte(LSC_PROG_CNTRL0);
+        self.write_zeros(3);
+        self.write_u32(ch.ctrl0.to_ctrl0());
+        // Write compression dictionary
+        if ch.compress {
+            let dict = self.build_comp_dict(ch);
+            self.write_byte(LSC_WRITE_COMP_DIC);
+            self.write_byte(0x80); // CRC check enable flag
+            self.write_zeros(2);
+            self.write_bytes(&dict);
+            self.insert_crc();
+            self.comp_dict = Some(dict);
+        }
+    }
+
+    // Get the final bitstream, bit reversing each byte if requested
+    // (for slave SPI from a microcontroller that shifts out LSB first)
+    fn finish(self, ch: &Chip) -> Vec<u8> {
+        let mut data = self.data;
+        if ch.reverse_bits {
+            for b in data.iter_mut() {
+                *b = b.reverse_bits();
+            }
+        }
+        data
+    }
+
+    // Write commands after the last frame, including the given IP config
+    fn write_footer(&mut self, ch: &Chip, ipconfig: &BTreeMap<u32, u8>) {
+        // The SED CRC isn't written, as Chip::compute_sed_crc hasn't been
+        // confirmed against Radiant and a wrong CRC would raise SED errors
+        if ch.sed_crc {
+            warn!("not writing SED CRC, as the CRC algorithm is unconfirmed");
+        }
         // Write power control
-        b.write_byte(LSC_POWER_CTRL);
-        b.write_zeros(2);
-        b.write_byte(0x01);
-        b.write_padding(512);
+        self.write_byte(LSC_POWER_CTRL);
+        self.write_zeros(2);
+        self.write_byte(0x01);
+        self.write_padding(512);
         // Write IP config
-        b.write_ip_config(ch);
+        self.write_ip_config(ch, ipconfig);
         // Write usercode
-        b.write_byte(ISC_PROGRAM_USERCODE);
-        b.write_byte(0x80); // CRC check enable flag
-        b.write_zeros(2);
-        b.write_u32(0x00000000);
-        b.insert_crc();
-        b.write_padding(15);
+        self.write_byte(ISC_PROGRAM_USERCODE);
+        self.write_byte(0x80); // CRC check enable flag
+        self.write_zeros(2);
+        self.write_u32(ch.usercode);
+        self.insert_crc();
+        self.write_padding(15);
+        // Disable readback
+        if ch.security == SecurityMode::ReadbackDisabled {
+            self.write_byte(ISC_PROGRAM_SECURITY);
+            self.write_zeros(3);
+            self.write_padding(4);
+        }
         // Program DONE
-        b.write_byte(ISC_PROGRAM_DONE);
+        self.write_byte(ISC_PROGRAM_DONE);
+        self.write_zeros(3);
+        self.write_padding(4);
+    }
+
+    // Serialise a headerless jump bitstream, which makes the configuration
+    // engine continue from `address` in SPI flash using the read opcode `spi_mode`
+    pub fn serialise_jump(spi_mode: u8, address: u32) -> Vec<u8> {
+        let mut b = BitstreamParser::new(&[]);
+        b.write_bytes(&PREAMBLE);
+        b.write_padding(4);
+        b.write_byte(SPI_MODE);
+        b.write_byte(spi_mode);
+        b.write_zeros(2);
+        b.write_byte(JUMP);
         b.write_zeros(3);
+        b.write_u32(address);
         b.write_padding(4);
         return b.data;
     }
@@ -195,12 +809,20 @@ impl BitstreamParser {
         return self.ecc14;
     }
 
+    // Error for running out of data
+    fn eof_error(&self) -> BitstreamError {
+        BitstreamError::UnexpectedEof {
+            offset: self.data.len(),
+            command: self.curr_cmd,
+        }
+    }
+
     // Get a single byte, updating the CRC
-    fn get_byte(&mut self) -> u8 {
-        let val = self.data[self.index];
+    fn get_byte(&mut self) -> Result<u8, BitstreamError> {
+        let val = *self.data.get(self.index).ok_or_else(|| self.eof_error())?;
         self.index += 1;
         self.update_crc16(val);
-        return val;
+        Ok(val)
     }
     // Write a byte into the bitstream, updating the CRC
     fn write_byte(&mut self, b: u8) {
@@ -212,13 +834,13 @@ impl BitstreamParser {
         self.data.push(b);
     }
     // Gets an opcode byte, updating the CRC if it isn't a dummy opcode (0xFF)
-    fn get_opcode_byte(&mut self) -> u8 {
-        let val = self.data[self.index];
+    fn get_opcode_byte(&mut self) -> Result<u8, BitstreamError> {
+        let val = *self.data.get(self.index).ok_or_else(|| self.eof_error())?;
         self.index += 1;
         if val != DUMMY {
             self.update_crc16(val);
         }
-        return val;
+        Ok(val)
     }
     // Checks if the stream matches a preamble token, consuming
     // the token and returning true if it does
@@ -234,18 +856,18 @@ impl BitstreamParser {
         }
     }
     // Get a 16-bit big-endian word
-    fn get_u16(&mut self) -> u16 {
-        let mut val = (self.get_byte() as u16) << 8;
-        val |= self.get_byte() as u16;
-        return val;
+    fn get_u16(&mut self) -> Result<u16, BitstreamError> {
+        let mut val = (self.get_byte()? as u16) << 8;
+        val |= self.get_byte()? as u16;
+        Ok(val)
     }
     // Get a 32-bit big-endian word
-    fn get_u32(&mut self) -> u32 {
-        let mut val = (self.get_byte() as u32) << 24;
-        val |= (self.get_byte() as u32) << 16;
-        val |= (self.get_byte() as u32) << 8;
-        val |= self.get_byte() as u32;
-        return val;
+    fn get_u32(&mut self) -> Result<u32, BitstreamError> {
+        let mut val = (self.get_byte()? as u32) << 24;
+        val |= (self.get_byte()? as u32) << 16;
+        val |= (self.get_byte()? as u32) << 8;
+        val |= self.get_byte()? as u32;
+        Ok(val)
     }
     // Write a 16-bit big-endian word
     fn write_u16(&mut self, h: u16) {
@@ -260,16 +882,66 @@ impl BitstreamParser {
         self.write_byte((w & 0xFF) as u8);
     }
     // Copy bytes
-    fn copy_bytes(&mut self, dest: &mut [u8]) {
+    fn copy_bytes(&mut self, dest: &mut [u8]) -> Result<(), BitstreamError> {
         for i in 0..dest.len() {
-            dest[i] = self.get_byte();
+            dest[i] = self.get_byte()?;
         }
+        Ok(())
     }
     // Skip bytes
-    fn skip_bytes(&mut self, len: usize) {
+    fn skip_bytes(&mut self, len: usize) -> Result<(), BitstreamError> {
         for _ in 0..len {
-            self.get_byte();
+            self.get_byte()?;
         }
+        Ok(())
+    }
+    // Read a compressed frame of `len` bytes, see write_compressed_frame for the encoding
+    fn get_compressed_frame(&mut self, dict: &[u8; 8], len: usize) -> Result<Vec<u8>, BitstreamError> {
+        let padded_len = len + (8 - len % 8) % 8;
+        let mut curr_byte = 0x00;
+        let mut bits_left = 0;
+        let mut get_bits = |p: &mut Self, width: usize| -> Result<u8, BitstreamError> {
+            let mut val = 0x00;
+            for _ in 0..width {
+                if bits_left == 0 {
+                    curr_byte = p.get_byte()?;
+                    bits_left = 8;
+                }
+                bits_left -= 1;
+                val = (val << 1) | ((curr_byte >> bits_left) & 0x1);
+            }
+            Ok(val)
+        };
+        let mut frame_bytes = Vec::with_capacity(padded_len);
+        for _ in 0..padded_len {
+            let b = if get_bits(self, 1)? == 0 {
+                0x00
+            } else if get_bits(self, 1)? == 0 {
+                if get_bits(self, 1)? == 0 {
+                    dict[get_bits(self, 3)? as usize]
+                } else {
+                    1 << get_bits(self, 3)?
+                }
+            } else {
+                get_bits(self, 8)?
+            };
+            frame_bytes.push(b);
+        }
+        // Discard start padding
+        Ok(frame_bytes.split_off(padded_len - len))
+    }
+    // Check that a fixed field has the expected value
+    fn check_value(&self, offset: usize, field: &'static str, expected: u32, found: u32) -> Result<(), BitstreamError> {
+        if expected != found {
+            return Err(BitstreamError::UnexpectedValue {
+                offset,
+                command: self.curr_cmd,
+                field,
+                expected,
+                found,
+            });
+        }
+        Ok(())
     }
     // Write a number of zeroes into the bitstream
     fn write_zeros(&mut self, len: usize) {
@@ -298,44 +970,144 @@ impl BitstreamParser {
         self.write_zeros(3);
         self.write_u32(addr);
     }
-    fn write_frames(&mut self, c: &Chip, start_addr: u32, count: usize) {
-        self.write_byte(LSC_PROG_INCR_RTI);
-        self.write_byte(0x91); // frame load settings
-        self.write_u16(count.try_into().unwrap());
+    // Get the content of a frame, including ECC, which doesn't cover ecc_mask bits
+    fn build_frame(&mut self, c: &Chip, frame_addr: u32) -> Vec<u8> {
         let bits_per_frame = c.data.bits_per_frame;
         let pad_bits = c.data.frame_ecc_bits + c.data.pad_bits_after_frame;
         let mut frame_bytes = vec![0 as u8; (bits_per_frame + 14 + 7) / 8];
         let total_frame_bytes = frame_bytes.len();
+        let frame_idx = c.frame_addr_to_idx(frame_addr);
+        self.ecc14 = ECC_INIT;
+        for j in (0..bits_per_frame).rev() {
+            let ofs = (j + pad_bits) as usize;
+            let value = c.cram.get(frame_idx, j);
+            let masked = self.ecc_mask.contains(&(frame_idx, j));
+            self.update_ecc(value && !masked);
+            if value {
+                frame_bytes[(total_frame_bytes - 1) - (ofs / 8)] |= 1 << (ofs % 8);
+            }
+        }
+        let ecc = self.finalise_ecc();
+        frame_bytes[total_frame_bytes - 2] |= ((ecc >> 8) & 0x3F) as u8;
+        frame_bytes[total_frame_bytes - 1] |= (ecc & 0xFF) as u8;
+        frame_bytes
+    }
+    fn write_frames(&mut self, c: &Chip, start_addr: u32, count: usize) {
+        self.write_byte(match self.comp_dict {
+            Some(_) => LSC_PROG_INCR_CMP,
+            None => LSC_PROG_INCR_RTI,
+        });
+        self.write_byte(0x91); // frame load settings
+        self.write_u16(count.try_into().unwrap());
         for f in 0..count {
             let frame_addr: u32 = start_addr + (f as u32);
-            let frame_idx = c.frame_addr_to_idx(frame_addr);
-            self.ecc14 = ECC_INIT;
-            for b in frame_bytes.iter_mut() {
-                *b = 0;
-            }
-            for j in (0..bits_per_frame).rev() {
-                let ofs = (j + pad_bits) as usize;
-                let value = c.cram.get(frame_idx, j);
-                self.update_ecc(value);
-                if value {
-                    frame_bytes[(total_frame_bytes - 1) - (ofs / 8)] |= 1 << (ofs % 8);
-                }
+            let frame_bytes = self.build_frame(c, frame_addr);
+            match self.comp_dict {
+                Some(dict) => self.write_compressed_frame(&frame_bytes, &dict),
+                None => self.write_bytes(&frame_bytes),
             }
-            let ecc = self.finalise_ecc();
-            frame_bytes[total_frame_bytes - 2] |= ((ecc >> 8) & 0x3F) as u8;
-            frame_bytes[total_frame_bytes - 1] |= (ecc & 0xFF) as u8;
-            self.write_bytes(&frame_bytes);
             self.insert_crc();
             self.write_byte(0xFF);
         }
     }
-    fn write_ip_config(&mut self, c: &Chip) {
+    // All frame addresses written to the device, in bitstream order
+    fn frame_addresses(c: &Chip) -> impl Iterator<Item = u32> {
+        let main_frames = (c.data.frames - (32 + c.tap_frame_count)) as u32;
+        (0x8000..0x8020)
+            .chain(0x0000..main_frames)
+            .chain(0x8020..(0x8020 + c.tap_frame_count as u32))
+    }
+    // Pick the compression dictionary: the eight most common bytes in the frame data
+    // that don't already have a short encoding (zero and one-hot bytes)
+    fn build_comp_dict(&mut self, c: &Chip) -> [u8; 8] {
+        let mut counts = [0usize; 256];
+        for frame_addr in BitstreamParser::frame_addresses(c) {
+            for b in self.build_frame(c, frame_addr) {
+                counts[b as usize] += 1;
+            }
+        }
+        let mut candidates: Vec<u8> = (0..=255u8)
+            .filter(|&b| b.count_ones() > 1 && counts[b as usize] > 0)
+            .collect();
+        candidates.sort_by_key(|&b| (std::cmp::Reverse(counts[b as usize]), b));
+        let mut dict = [0x00; 8];
+        for (d, &b) in dict.iter_mut().zip(candidates.iter()) {
+            *d = b;
+        }
+        dict
+    }
+    // Write a frame in compressed form. The frame is zero-padded at the start
+    // to a multiple of 8 bytes, then each byte is encoded MSB first as:
+    //   0              0x00
+    //   100 iii        dictionary entry iii
+    //   101 bbb        one-hot byte 1 << bbb
+    //   11 dddddddd    literal byte
+    // and the result padded with zero bits to a whole number of bytes
+    fn write_compressed_frame(&mut self, frame_bytes: &[u8], dict: &[u8; 8]) {
+        let padded_len = frame_bytes.len() + (8 - frame_bytes.len() % 8) % 8;
+        let mut bits = Vec::new();
+        let mut push_bits = |val: u32, width: usize| {
+            for i in (0..width).rev() {
+                bits.push((val >> i) & 0x1 == 0x1);
+            }
+        };
+        for i in 0..padded_len {
+            let b = if i < (padded_len - frame_bytes.len()) {
+                0x00
+            } else {
+                frame_bytes[i - (padded_len - frame_bytes.len())]
+            };
+            if b == 0x00 {
+                push_bits(0b0, 1);
+            } else if let Some(idx) = dict.iter().position(|&d| d == b) {
+                push_bits(0b100, 3);
+                push_bits(idx as u32, 3);
+            } else if b.count_ones() == 1 {
+                push_bits(0b101, 3);
+                push_bits(b.trailing_zeros(), 3);
+            } else {
+                push_bits(0b11, 2);
+                push_bits(b as u32, 8);
+            }
+        }
+        for chunk in bits.chunks(8) {
+            let byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0x00, |acc, (i, &bit)| acc | ((bit as u8) << (7 - i)));
+            self.write_byte(byte);
+        }
+    }
+    fn write_ip_config(&mut self, c: &Chip, ipconfig: &BTreeMap<u32, u8>) {
+        // LRAM addresses are scaled by 8/10 when written (see below), which is
+        // only reversible at the start of a 5 byte bus frame. Fill out partly
+        // set LRAM frames with zeros so that every LRAM chunk starts on one
+        let lram_fill: BTreeSet<u32> = ipconfig
+            .keys()
+            .filter(|&&a| a & 0xFF000000 == 0x2E000000)
+            .flat_map(|&a| {
+                let frame_start = a - (a & 0x1FFFF) % 5;
+                frame_start..(frame_start + 5)
+            })
+            .filter(|a| !ipconfig.contains_key(a))
+            .collect();
+        let filled;
+        let ipconfig = if lram_fill.is_empty() {
+            ipconfig
+        } else {
+            filled = ipconfig
+                .iter()
+                .map(|(&a, &v)| (a, v))
+                .chain(lram_fill.into_iter().map(|a| (a, 0x00)))
+                .collect::<BTreeMap<u32, u8>>();
+            &filled
+        };
         // Create continguous chunks
         let mut last_addr = None;
         let mut curr_chunk : Option<(u32, Vec<u8>)> = None;
         let mut chunks = Vec::new();
         // The 0x0E000000 region is special
-        for (&addr, &val) in c.ipconfig.iter().filter(|(&a, _)| a & 0xFF000000 != 0x0E000000 ) {
+        for (&addr, &val) in ipconfig.iter().filter(|(&a, _)| a & 0xFF000000 != 0x0E000000 ) {
             if last_addr.is_none() || (last_addr.unwrap() + 1 != addr)
                 || (curr_chunk.is_some() && curr_chunk.as_ref().unwrap().1.len() >= 40960) {
                 // All cases where we start a new chunk
@@ -351,7 +1123,7 @@ impl BitstreamParser {
             chunks.push(curr_chunk.unwrap());
         }
         // PLL bits are written seperately, in reverse order for some reason
-        for (&addr, &val) in c.ipconfig.iter().filter(|(&a, _)| a & 0xFF000000 == 0x0E000000 ).rev() {
+        for (&addr, &val) in ipconfig.iter().filter(|(&a, _)| a & 0xFF000000 == 0x0E000000 ).rev() {
             chunks.push((addr, vec![val]))
         }
         // Write out chunks
@@ -363,6 +1135,8 @@ impl BitstreamParser {
             // Fixup LRAM addressing
             if adj_addr & 0xFF000000 == 0x2E000000 {
                 let ls = adj_addr & 0x1FFFF;
+                // Chunks are whole frames and split every 40960 bytes, so stay aligned
+                assert_eq!(ls % 5, 0, "unaligned LRAM chunk at 0x{:08X}", adj_addr);
                 let ms = adj_addr & 0xFFFE0000;
                 adj_addr = ms | ((ls * 8) / 10);
             }
@@ -399,12 +1173,21 @@ impl BitstreamParser {
     }
 
     // Consume and check crc16
-    fn check_crc16(&mut self) {
+    fn check_crc16(&mut self) -> Result<CrcStatus, BitstreamError> {
         self.finalise_crc16();
         let calc_crc16 = self.crc16;
-        let exp_crc16 = self.get_u16();
-        assert_eq!(calc_crc16, exp_crc16);
+        let offset = self.index;
+        let exp_crc16 = self.get_u16()?;
         self.crc16 = CRC16_INIT;
+        if calc_crc16 != exp_crc16 {
+            Ok(CrcStatus::Mismatch {
+                offset,
+                expected: calc_crc16,
+                found: exp_crc16,
+            })
+        } else {
+            Ok(CrcStatus::Ok)
+        }
     }
 
     // Finalise and insert CRC
@@ -418,14 +1201,69 @@ impl BitstreamParser {
         self.index >= self.data.len()
     }
 
+    fn push_metadata(&mut self, metadata: &str) {
+        if !metadata.is_empty() {
+            info!("metadata: {}", metadata);
+            self.emit(&ParseEvent::Metadata(metadata));
+        }
+        self.metadata.push(metadata.to_string());
+    }
+
+    // Detect a bitstream with every byte bit reversed, by which preamble comes first,
+    // and undo the reversal
+    fn detect_bit_reversal(&mut self) {
+        let reversed_preamble = reverse_bytes(&PREAMBLE);
+        let find = |pattern: &[u8]| self.data.windows(pattern.len()).position(|w| w == pattern);
+        let reversed = match (find(&PREAMBLE), find(&reversed_preamble)) {
+            (Some(n), Some(r)) => r < n,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        if reversed {
+            info!("bitstream is bit reversed");
+            self.emit(&ParseEvent::BitReversed);
+            for b in self.data.iter_mut() {
+                *b = b.reverse_bits();
+            }
+            self.bit_reversed = true;
+        }
+    }
+
+    // Find where a bitstream begins, given the offset `pos` of the first byte
+    // after any 0xFF erase padding: at an "LSCC" header, or at the preamble for
+    // headerless images, in either bit order. The parser handles bit reversed
+    // images itself once given data starting at this offset
+    pub fn find_bitstream_start(data: &[u8], pos: usize) -> Option<usize> {
+        let rest = &data[pos..];
+        if rest.starts_with(b"LSCC") || rest.starts_with(&reverse_bytes(b"LSCC")) {
+            return Some(pos);
+        }
+        // The leading 0xFF bytes of the preamble look like erase padding
+        let start = pos.checked_sub(2)?;
+        if data[start..].starts_with(&PREAMBLE) || data[start..].starts_with(&reverse_bytes(&PREAMBLE)) {
+            return Some(start);
+        }
+        None
+    }
+
+    // Whether the input was detected as bit reversed
+    pub fn is_bit_reversed(&self) -> bool {
+        self.bit_reversed
+    }
+
     // Process bitstream container
     // Consumes metadata up to and including preamble
-    fn parse_container(&mut self) -> Result<BitstreamType, &'static str> {
+    // Headerless images, and any leading 0xFF flash erase padding, are also accepted
+    fn parse_container(&mut self) -> Result<BitstreamType, BitstreamError> {
+        if self.index == 0 {
+            self.detect_bit_reversal();
+        }
         let mut in_metadata = false;
         let mut curr_meta = String::new();
         while !self.done() {
             if self.check_preamble(&PREAMBLE) {
-                println!("bitstream start at {}", self.index);
+                debug!("bitstream start at {}", self.index);
+                self.emit(&ParseEvent::BitstreamStart { offset: self.index });
                 return Ok(BitstreamType::NORMAL);
             }
             if !in_metadata && self.check_preamble(&COMMENT_START) {
@@ -434,7 +1272,7 @@ impl BitstreamParser {
             }
             if in_metadata && self.check_preamble(&COMMENT_END) {
                 if curr_meta.len() > 0 {
-                    self.metadata.push(curr_meta.to_string());
+                    self.push_metadata(&curr_meta);
                     curr_meta.clear();
                 }
                 in_metadata = false;
@@ -442,205 +1280,490 @@ impl BitstreamParser {
             }
             if in_metadata && self.check_preamble(&COMMENT_END_RDBK) {
                 if curr_meta.len() > 0 {
-                    self.metadata.push(curr_meta.to_string());
+                    self.push_metadata(&curr_meta);
                     curr_meta.clear();
                 }
                 return Ok(BitstreamType::READBACK);
             }
             if in_metadata {
-                let ch = self.get_byte();
+                let ch = self.get_byte()?;
                 if ch == 0x00 {
-                    if curr_meta.len() > 0 {
-                        println!("Metadata: {}", &curr_meta);
-                    }
-                    self.metadata.push(curr_meta.to_string());
+                    self.push_metadata(&curr_meta);
                     curr_meta.clear();
                 } else {
                     curr_meta.push(ch as char);
                 }
             } else {
-                self.get_byte();
+                self.get_byte()?;
             }
         }
-        Err("failed to find preamble")
+        Err(BitstreamError::NoPreamble)
     }
 
-    // Parse the bitstream itself
-    fn parse_bitstream(&mut self, db: &mut Database) -> Result<Chip, &'static str> {
-        let mut curr_frame = 0;
-        let mut bus_addr = 0;
-        let mut curr_chip = None;
-        while !self.done() {
-            let cmd = self.get_opcode_byte();
-            match cmd {
-                LSC_RESET_CRC => {
-                    println!("reset CRC");
-                    self.skip_bytes(3);
-                    self.crc16 = CRC16_INIT;
-                }
-                LSC_PROG_CNTRL0 => {
-                    self.skip_bytes(3);
-                    let ctrl0 = self.get_u32();
-                    println!("set CTRL0 to 0x{:08X}", ctrl0);
-                }
-                VERIFY_ID => {
-                    self.skip_bytes(3);
-                    let idcode = self.get_u32();
-                    let mut chip = Chip::from_idcode(db, idcode);
-                    chip.metadata = self.metadata.clone();
-                    curr_chip = Some(chip);
-                    println!("check IDCODE is 0x{:08X}", idcode);
-                }
-                LSC_INIT_ADDRESS => {
-                    self.skip_bytes(3);
-                    println!("reset frame address");
-                    curr_frame = 0;
-                }
-                LSC_WRITE_ADDRESS => {
-                    self.skip_bytes(3);
-                    curr_frame = self.get_u32();
-                    println!("set frame address to 0x{:08X}", curr_frame);
-                }
-                LSC_PROG_INCR_RTI => {
-                    let cfg = self.get_byte();
-                    let count = self.get_u16();
-                    let bits_per_frame: usize;
-                    let pad_bits: usize;
-                    let chip: &mut Chip;
-                    match curr_chip.as_mut() {
-                        Some(ch) => {
-                            bits_per_frame = ch.data.bits_per_frame;
-                            pad_bits = ch.data.frame_ecc_bits + ch.data.pad_bits_after_frame;
-                            chip = ch;
-                        }
+    // Decode the next command, returning None at the end of the bitstream
+    fn decode_command(&mut self, db: &mut Database) -> Result<Option<DecodedCommand>, BitstreamError> {
+        if self.done() {
+            self.curr_cmd = None;
+            return Ok(None);
+        }
+        let offset = self.index;
+        self.curr_cmd = None;
+        let cmd = self.get_opcode_byte()?;
+        self.curr_cmd = Some(cmd);
+        let mut crc = CrcStatus::Unchecked;
+        let command = match cmd {
+            DUMMY => {
+                let mut count = 1;
+                while !self.done() && self.data[self.index] == DUMMY {
+                    self.index += 1;
+                    count += 1;
+                }
+                BitstreamCommand::Padding { count }
+            }
+            LSC_RESET_CRC => {
+                self.skip_bytes(3)?;
+                self.crc16 = CRC16_INIT;
+                BitstreamCommand::ResetCrc
+            }
+            LSC_PROG_CNTRL0 => {
+                self.skip_bytes(3)?;
+                let ctrl0 = self.get_u32()?;
+                BitstreamCommand::ProgCntrl0 { ctrl0 }
+            }
+            VERIFY_ID => {
+                self.skip_bytes(3)?;
+                let idcode_offset = self.index;
+                let idcode = self.get_u32()?;
+                let mut chip = Chip::try_from_idcode(db, idcode).ok_or(BitstreamError::UnknownIdcode {
+                    offset: idcode_offset,
+                    command: self.curr_cmd,
+                    idcode,
+                })?;
+                chip.metadata = self.metadata.clone();
+                self.curr_chip = Some(chip);
+                BitstreamCommand::VerifyId { idcode }
+            }
+            LSC_WRITE_COMP_DIC => {
+                let flags = self.get_byte()?;
+                self.skip_bytes(2)?;
+                let mut dict = [0x00; 8];
+                self.copy_bytes(&mut dict)?;
+                if flags & 0x80 == 0x80 {
+                    crc = self.check_crc16()?;
+                }
+                self.comp_dict = Some(dict);
+                BitstreamCommand::WriteCompDic { flags, dict }
+            }
+            LSC_INIT_ADDRESS => {
+                self.skip_bytes(3)?;
+                self.curr_frame = 0;
+                BitstreamCommand::InitAddress
+            }
+            LSC_WRITE_ADDRESS => {
+                self.skip_bytes(3)?;
+                self.curr_frame = self.get_u32()?;
+                BitstreamCommand::WriteAddress {
+                    address: self.curr_frame,
+                }
+            }
+            LSC_PROG_INCR_RTI | LSC_PROG_INCR_CMP => {
+                let cfg_offset = self.index;
+                let config = self.get_byte()?;
+                let count = self.get_u16()?;
+                let bits_per_frame = match self.curr_chip.as_ref() {
+                    Some(ch) => ch.data.bits_per_frame,
+                    None => {
+                        return Err(BitstreamError::NoDevice {
+                            offset,
+                            command: self.curr_cmd,
+                        });
+                    }
+                };
+                self.check_value(cfg_offset, "frame load settings", 0x91, config as u32)?;
+                let dict = match (cmd, self.comp_dict) {
+                    (LSC_PROG_INCR_CMP, None) => {
+                        return Err(BitstreamError::NoDictionary {
+                            offset,
+                            command: self.curr_cmd,
+                        });
+                    }
+                    (LSC_PROG_INCR_CMP, Some(d)) => Some(d),
+                    _ => None,
+                };
+                let address = self.curr_frame;
+                let mut frames = Vec::new();
+                self.frame_offsets.clear();
+                crc = CrcStatus::Ok;
+                for _ in 0..count {
+                    self.frame_offsets.push(self.index);
+                    let frame_len = (bits_per_frame + 14 + 7) / 8;
+                    let frame_bytes = match dict {
+                        Some(d) => self.get_compressed_frame(&d, frame_len)?,
                         None => {
-                            return Err("got bitstream before idcode");
+                            let mut frame_bytes = vec![0 as u8; frame_len];
+                            self.copy_bytes(&mut frame_bytes)?;
+                            frame_bytes
                         }
+                    };
+                    let frame_crc = self.check_crc16()?;
+                    if crc == CrcStatus::Ok {
+                        crc = frame_crc;
                     }
-                    println!("write {} frames at 0x{:08x}", count, curr_frame);
-                    let mut frame_bytes = vec![0 as u8; (bits_per_frame + 14 + 7) / 8];
-                    assert_eq!(cfg, 0x91);
-                    for _ in 0..count {
-                        self.copy_bytes(&mut frame_bytes);
-                        self.ecc14 = ECC_INIT;
-                        for j in (0..bits_per_frame).rev() {
-                            let ofs = (j + pad_bits) as usize;
-                            if ((frame_bytes[(frame_bytes.len() - 1) - (ofs / 8)] >> (ofs % 8))
-                                & 0x01)
-                                == 0x01
-                            {
-                                let decoded_frame = chip.frame_addr_to_idx(curr_frame);
-                                if decoded_frame < chip.cram.frames {
-                                    chip.cram.set(decoded_frame, j, true);
-                                }
-                                if self.verbose {
-                                    println!("F0x{:08x}B{:04}", curr_frame, j);
-                                }
-                                self.update_ecc(true);
-                            } else {
-                                self.update_ecc(false);
-                            }
-                        }
-                        let parity = ((frame_bytes[frame_bytes.len() - 2] as u16) << 8
-                            | (frame_bytes[frame_bytes.len() - 1] as u16))
-                            & 0x3FFF;
-                        let exp_parity = self.finalise_ecc();
+                    let trailer_offset = self.index;
+                    let d = self.get_byte()?;
+                    self.check_value(trailer_offset, "frame trailer", 0xFF, d as u32)?;
+                    frames.push(frame_bytes);
+                    self.curr_frame = self.curr_frame.wrapping_add(1);
+                }
+                if dict.is_some() {
+                    BitstreamCommand::ProgIncrCmp {
+                        config,
+                        address,
+                        frames,
+                    }
+                } else {
+                    BitstreamCommand::ProgIncrRti {
+                        config,
+                        address,
+                        frames,
+                    }
+                }
+            }
+            LSC_POWER_CTRL => {
+                self.skip_bytes(2)?;
+                let value = self.get_byte()?;
+                BitstreamCommand::PowerCtrl { value }
+            }
+            ISC_PROGRAM_USERCODE => {
+                let flags = self.get_byte()?;
+                self.skip_bytes(2)?;
+                let usercode = self.get_u32()?;
+                if flags & 0x80 == 0x80 {
+                    crc = self.check_crc16()?;
+                }
+                BitstreamCommand::ProgramUsercode { flags, usercode }
+            }
+            LSC_PROG_SED_CRC => {
+                self.skip_bytes(3)?;
+                let crc = self.get_u32()?;
+                BitstreamCommand::ProgSedCrc { crc }
+            }
+            ISC_PROGRAM_SECURITY => {
+                let flags = self.get_byte()?;
+                self.skip_bytes(2)?;
+                BitstreamCommand::ProgramSecurity { flags }
+            }
+            LSC_BUS_ADDRESS => {
+                self.skip_bytes(3)?;
+                self.bus_addr = self.get_u32()?;
+                BitstreamCommand::BusAddress {
+                    address: self.bus_addr,
+                }
+            }
+            LSC_BUS_WRITE => {
+                let config = self.get_byte()?;
+                let frame_count = self.get_u16()? as usize;
+                let address = self.bus_addr;
+                let chip = self.curr_chip.as_ref().ok_or(BitstreamError::NoDevice {
+                    offset,
+                    command: self.curr_cmd,
+                })?;
+                let frame_size = chip.try_get_bus_frame_size(address).ok_or(
+                    BitstreamError::InvalidBusAddress {
+                        offset,
+                        command: self.curr_cmd,
+                        address,
+                    },
+                )?;
+                let byte_count = frame_count * frame_size;
+                self.bus_addr = (byte_count as u64 + address as u64).try_into().map_err(|_| {
+                    BitstreamError::InvalidBusAddress {
+                        offset,
+                        command: self.curr_cmd,
+                        address,
+                    }
+                })?;
+                let mut data = vec![0u8; byte_count];
+                self.copy_bytes(&mut data)?;
+                if config & 0x80 == 0x80 {
+                    crc = self.check_crc16()?;
+                }
+                BitstreamCommand::BusWrite {
+                    config,
+                    address,
+                    frame_count,
+                    data,
+                }
+            }
+            ISC_PROGRAM_DONE => {
+                self.skip_bytes(3)?;
+                BitstreamCommand::ProgramDone
+            }
+            SPI_MODE => {
+                let mode = self.get_byte()?;
+                self.skip_bytes(2)?;
+                BitstreamCommand::SpiMode { mode }
+            }
+            JUMP => {
+                self.skip_bytes(3)?;
+                let address = self.get_u32()?;
+                BitstreamCommand::Jump { address }
+            }
+            _ => {
+                return Err(BitstreamError::UnknownCommand {
+                    offset,
+                    command: cmd,
+                });
+            }
+        };
+        Ok(Some(DecodedCommand {
+            offset,
+            length: self.index - offset,
+            command,
+            crc,
+        }))
+    }
+
+    // Load a run of frames into the chip being parsed
+    fn load_frames(&mut self, cmd_offset: usize, address: u32, frames: &[Vec<u8>]) -> Result<(), BitstreamError> {
+        let mut chip = self.curr_chip.take().ok_or(BitstreamError::NoDevice {
+            offset: cmd_offset,
+            command: self.curr_cmd,
+        })?;
+        let bits_per_frame = chip.data.bits_per_frame;
+        let pad_bits = chip.data.frame_ecc_bits + chip.data.pad_bits_after_frame;
+        for (i, frame_bytes) in frames.iter().enumerate() {
+            let frame_addr = address.wrapping_add(i as u32);
+            let frame_offset = self.frame_offsets.get(i).copied().unwrap_or(cmd_offset);
+            let decoded_frame = match chip.try_frame_addr_to_idx(frame_addr) {
+                Some(f) => f,
+                None => {
+                    // Vendor bitstreams may write frames beyond those that are
+                    // known for the device, these are skipped as they always were
+                    warn!(
+                        "skipping unknown frame address 0x{:08X} at offset 0x{:08X}",
+                        frame_addr, frame_offset
+                    );
+                    continue;
+                }
+            };
+            self.ecc14 = ECC_INIT;
+            for j in (0..bits_per_frame).rev() {
+                let ofs = (j + pad_bits) as usize;
+                if ((frame_bytes[(frame_bytes.len() - 1) - (ofs / 8)] >> (ofs % 8))
+                    & 0x01)
+                    == 0x01
+                {
+                    chip.cram.set(decoded_frame, j, true);
+                    if self.verbose {
+                        trace!("F0x{:08x}B{:04}", frame_addr, j);
+                    }
+                    self.update_ecc(true);
+                } else {
+                    self.update_ecc(false);
+                }
+            }
+            let parity = ((frame_bytes[frame_bytes.len() - 2] as u16) << 8
+                | (frame_bytes[frame_bytes.len() - 1] as u16))
+                & 0x3FFF;
+            let exp_parity = self.finalise_ecc();
 
-                        // ECC calculation here is actually occasionally unsound,
-                        // as LUT RAM initialisation is masked from ECC calculation
-                        // as it changes at runtime. But it is too early to check this here.
+            // ECC calculation here is actually occasionally unsound,
+            // as LUT RAM initialisation is masked from ECC calculation
+            // as it changes at runtime. But it is too early to check this here,
+            // so strict checking is done by check_frame_ecc once all frames are loaded.
+            if self.strict_ecc {
+                self.frame_ecc.push((frame_offset, frame_addr, parity));
+            }
 
-                        if self.verbose {
-                            println!("F0x{:08x}P{:014b}E{:014b}", curr_frame, parity, exp_parity);
+            if self.verbose {
+                trace!("F0x{:08x}P{:014b}E{:014b}", frame_addr, parity, exp_parity);
+            }
+            self.emit(&ParseEvent::Frame {
+                address: frame_addr,
+                index: decoded_frame,
+                ecc: parity,
+                computed_ecc: exp_parity,
+            });
+        }
+        self.curr_chip = Some(chip);
+        Ok(())
+    }
+
+    // Parse the bitstream itself
+    fn parse_bitstream(&mut self, db: &mut Database) -> Result<Chip, BitstreamError> {
+        while let Some(dc) = self.decode_command(db)? {
+            if let CrcStatus::Mismatch {
+                offset,
+                expected,
+                found,
+            } = dc.crc
+            {
+                return Err(BitstreamError::CrcMismatch {
+                    offset,
+                    command: self.curr_cmd,
+                    expected,
+                    found,
+                });
+            }
+            self.emit(&ParseEvent::Command(&dc));
+            match dc.command {
+                BitstreamCommand::ResetCrc => debug!("reset CRC"),
+                BitstreamCommand::ProgCntrl0 { ctrl0 } => {
+                    debug!("set CTRL0 to 0x{:08X}", ctrl0);
+                    if let Some(chip) = self.curr_chip.as_mut() {
+                        chip.ctrl0 = ConfigControl::from_ctrl0(ctrl0);
+                    }
+                }
+                BitstreamCommand::VerifyId { idcode } => debug!("check IDCODE is 0x{:08X}", idcode),
+                BitstreamCommand::InitAddress => debug!("reset frame address"),
+                BitstreamCommand::WriteAddress { address } => {
+                    debug!("set frame address to 0x{:08X}", address)
+                }
+                BitstreamCommand::ProgIncrRti {
+                    address,
+                    ref frames,
+                    ..
+                }
+                | BitstreamCommand::ProgIncrCmp {
+                    address,
+                    ref frames,
+                    ..
+                } => {
+                    debug!("write {} frames at 0x{:08x}", frames.len(), address);
+                    self.load_frames(dc.offset, address, frames)?;
+                }
+                BitstreamCommand::PowerCtrl { value } => debug!("power control: {}", value),
+                BitstreamCommand::ProgramUsercode { usercode, .. } => {
+                    debug!("set usercode to 0x{:08X}", usercode);
+                    if let Some(chip) = self.curr_chip.as_mut() {
+                        chip.usercode = usercode;
+                    }
+                }
+                BitstreamCommand::ProgSedCrc { crc } => {
+                    debug!("check SED CRC is 0x{:08X}", crc);
+                    let chip = self.curr_chip.as_mut().ok_or(BitstreamError::NoDevice {
+                        offset: dc.offset,
+                        command: Some(LSC_PROG_SED_CRC),
+                    })?;
+                    // Check against the frames loaded so far
+                    if self.check_sed_crc {
+                        let expected = chip.compute_sed_crc();
+                        if crc != expected {
+                            return Err(BitstreamError::SedCrcMismatch {
+                                offset: dc.offset,
+                                expected,
+                                found: crc,
+                            });
                         }
-                        self.check_crc16();
-                        let d = self.get_byte();
-                        assert_eq!(d, 0xFF);
-                        curr_frame += 1;
                     }
+                    chip.sed_crc = true;
                 }
-                LSC_POWER_CTRL => {
-                    self.skip_bytes(2);
-                    let pwr = self.get_byte();
-                    println!("power control: {}", pwr);
-                }
-                ISC_PROGRAM_USERCODE => {
-                    let cmp_crc = self.get_byte() & 0x80 == 0x80;
-                    self.skip_bytes(2);
-                    let usercode = self.get_u32();
-                    println!("set usercode to 0x{:08X}", usercode);
-                    if cmp_crc {
-                        self.check_crc16();
+                BitstreamCommand::ProgramSecurity { .. } => {
+                    debug!("disable readback");
+                    if let Some(chip) = self.curr_chip.as_mut() {
+                        chip.security = SecurityMode::ReadbackDisabled;
                     }
                 }
-                LSC_BUS_ADDRESS => {
-                    self.skip_bytes(3);
-                    bus_addr = self.get_u32();
-                }
-                LSC_BUS_WRITE => {
-                    let config = self.get_byte();
-                    let cmp_crc = config & 0x80 == 0x80;
-                    let frame_count = self.get_u16() as usize;
-                    let chip = curr_chip
-                        .as_mut()
-                        .expect("got bus write without chip setup");
-                    let byte_count = frame_count * chip.get_bus_frame_size(bus_addr);
-                    for _i in 0..byte_count {
-                        chip.ipconfig.insert(bus_addr, self.get_byte());
-                        bus_addr += 1;
+                BitstreamCommand::BusWrite {
+                    address, ref data, ..
+                } => {
+                    // decode_command guarantees the chip exists
+                    let chip = self.curr_chip.as_mut().unwrap();
+                    let mut address = address;
+                    // Undo the LRAM addressing fixup in write_ip_config; this is
+                    // exact as LRAM writes start on a 5 byte frame
+                    if address & 0xFF000000 == 0x2E000000 {
+                        let ls = address & 0x1FFFF;
+                        let ms = address & 0xFFFE0000;
+                        address = ms | ((ls * 10) / 8);
                     }
-                    if cmp_crc {
-                        self.check_crc16();
+                    for (i, &d) in data.iter().enumerate() {
+                        chip.ipconfig.insert(address + (i as u32), d);
                     }
                 }
-                ISC_PROGRAM_DONE => {
-                    self.skip_bytes(3);
-                    println!("done");
+                BitstreamCommand::ProgramDone => {
+                    // Anything after this, in a flash dump for example, isn't part of the bitstream
+                    debug!("done");
+                    break;
                 }
-                DUMMY => {}
-                _ => {
-                    println!("unknown command 0x{:02X} at {}", cmd, self.index);
-                    return Err("unknown bitstream command");
+                BitstreamCommand::SpiMode { mode } => debug!("SPI read mode 0x{:02X}", mode),
+                BitstreamCommand::Jump { address } => debug!("jump to 0x{:08X}", address),
+                BitstreamCommand::WriteCompDic { .. } => {
+                    debug!("compressed frame data");
+                    if let Some(chip) = self.curr_chip.as_mut() {
+                        chip.compress = true;
+                    }
                 }
+                BitstreamCommand::Padding { .. } | BitstreamCommand::BusAddress { .. } => {}
             }
         }
-        match curr_chip {
-            Some(x) => Ok(x),
-            None => Err("missing bitstream content"),
+        match self.curr_chip.take() {
+            Some(x) => {
+                if self.strict_ecc {
+                    self.check_frame_ecc(db, &x)?;
+                }
+                Ok(x)
+            }
+            None => Err(BitstreamError::NoContent),
+        }
+    }
+
+    // Check the ECC of all loaded frames, now that the LUT RAM configuration is known
+    fn check_frame_ecc(&mut self, db: &mut Database, chip: &Chip) -> Result<(), BitstreamError> {
+        let masked = chip.ecc_masked_bits(db);
+        let frame_ecc = std::mem::take(&mut self.frame_ecc);
+        for (offset, frame_addr, parity) in frame_ecc {
+            let frame_idx = chip.frame_addr_to_idx(frame_addr);
+            let with_mask = self.compute_frame_ecc(chip, frame_idx, Some(&masked));
+            if parity != with_mask {
+                return Err(BitstreamError::EccMismatch {
+                    offset,
+                    address: frame_addr,
+                    expected: with_mask,
+                    found: parity,
+                });
+            }
         }
+        Ok(())
     }
 
-    fn parse_readback_bistream(&mut self, db: &mut Database) -> Result<Chip, &'static str> {
+    // Compute the ECC of a frame from the CRAM, treating masked bits as zero
+    fn compute_frame_ecc(&mut self, chip: &Chip, frame_idx: usize, masked: Option<&BTreeSet<(usize, usize)>>) -> u16 {
+        self.ecc14 = ECC_INIT;
+        for j in (0..chip.data.bits_per_frame).rev() {
+            let is_masked = masked.is_some_and(|m| m.contains(&(frame_idx, j)));
+            self.update_ecc(chip.cram.get(frame_idx, j) && !is_masked);
+        }
+        self.finalise_ecc()
+    }
+
+    fn parse_readback_bistream(&mut self, db: &mut Database) -> Result<Chip, BitstreamError> {
         // 4 byte IDCODE
-        let idcode = self.get_u32();
-        let mut chip = Chip::from_idcode(db, idcode);
+        let idcode_offset = self.index;
+        let idcode = self.get_u32()?;
+        let mut chip = Chip::try_from_idcode(db, idcode).ok_or(BitstreamError::UnknownIdcode {
+            offset: idcode_offset,
+            command: None,
+            idcode,
+        })?;
         // 4 bytes 00 padding
-        self.skip_bytes(4);
+        self.skip_bytes(4)?;
         // 20 bytes FF padding
-        self.skip_bytes(20);
+        self.skip_bytes(20)?;
         
         let mut frame_bytes = vec![0 as u8; (chip.data.bits_per_frame + 14 + 7) / 8];
         let mut padding = [0 as u8; 4];
 
         for i in 0..chip.data.frames {
-            let frame_index = if i < 16 {
-                // right side IO
-                (15 - i) + (16 + chip.tap_frame_count)
-            } else if i >= 16 && i < 32 {
-                // left side IO
-                15 - (i - 16)
-            } else if i >= 32 && i < (chip.data.frames - chip.tap_frame_count) {
-                // main bitstream
-                (chip.data.frames - 1) - (i - 32)
-            } else {
-                // tap bits
-                ((chip.tap_frame_count - 1) - (i - (chip.data.frames - chip.tap_frame_count))) + 16
-            };
+            let frame_index = BitstreamParser::readback_frame_index(&chip, i);
             // 4 bytes dummy
-            self.copy_bytes(&mut padding);
-            assert_eq!(padding, [0xFF, 0xFF, 0xFF, 0xFF]);
+            let padding_offset = self.index;
+            self.copy_bytes(&mut padding)?;
+            self.check_value(padding_offset, "readback frame padding", 0xFFFFFFFF, u32::from_be_bytes(padding))?;
             // frame data
-            self.copy_bytes(&mut frame_bytes);
+            let frame_offset = self.index;
+            self.copy_bytes(&mut frame_bytes)?;
             for j in 0..(chip.data.bits_per_frame + chip.data.pad_bits_after_frame) {
                 // TODO: bit ordering inside frames
                 let ofs = (14 + j) as usize;
@@ -651,14 +1774,105 @@ impl BitstreamParser {
                     }
                 } else {
                     // padding bit, should be one
-                    assert!(val);
+                    self.check_value(frame_offset, "readback frame padding bit", 1, val as u32)?;
                 }
             }
         }
         Ok(chip)
     }
 
-    pub fn parse(&mut self, db: &mut Database) -> Result<Chip, &'static str> {
+    // Index into the CRAM of the i'th frame in a readback image
+    fn readback_frame_index(chip: &Chip, i: usize) -> usize {
+        if i < 16 {
+            // right side IO
+            (15 - i) + (16 + chip.tap_frame_count)
+        } else if i >= 16 && i < 32 {
+            // left side IO
+            15 - (i - 16)
+        } else if i >= 32 && i < (chip.data.frames - chip.tap_frame_count) {
+            // main bitstream
+            (chip.data.frames - 1) - (i - 32)
+        } else {
+            // tap bits
+            ((chip.tap_frame_count - 1) - (i - (chip.data.frames - chip.tap_frame_count))) + 16
+        }
+    }
+
+    // Serialise the image expected when reading back a device configured with `ch`
+    pub fn serialise_readback(ch: &Chip) -> Vec<u8> {
+        let mut b = BitstreamParser::new(&[]);
+        b.write_readback(ch, &|f, j| ch.cram.get(f, j), false);
+        return b.data;
+    }
+
+    // Serialise a readback mask for `ch`, in the same layout as the readback image
+    // Set bits are those that should be ignored when comparing: the `masked` CRAM bits
+    // (see Chip::ecc_masked_bits), as well as the ECC and padding in each frame
+    pub fn serialise_readback_mask(ch: &Chip, masked: &BTreeSet<(usize, usize)>) -> Vec<u8> {
+        let mut b = BitstreamParser::new(&[]);
+        b.write_readback(ch, &|f, j| masked.contains(&(f, j)), true);
+        return b.data;
+    }
+
+    // Write a readback image, getting the CRAM bits from `get_bit`
+    // If `mask` is set, ECC and padding bits are also set
+    fn write_readback(&mut self, ch: &Chip, get_bit: &dyn Fn(usize, usize) -> bool, mask: bool) {
+        self.write_string("LSCC"); // magic
+        self.write_bytes(&COMMENT_START); // metadata start
+        for (i, m) in ch.metadata.iter().enumerate() {
+            self.write_string(m);
+            if i < (ch.metadata.len() - 1) {
+                self.write_byte(0x00); // terminator
+            }
+        }
+        self.write_bytes(&COMMENT_END_RDBK); // metadata end
+        self.write_u32(ch.get_idcode());
+        self.write_zeros(4);
+        self.write_padding(20);
+        let bits_per_frame = ch.data.bits_per_frame;
+        for i in 0..ch.data.frames {
+            let frame_index = BitstreamParser::readback_frame_index(ch, i);
+            self.write_padding(4);
+            let mut frame_bytes = vec![0x00; (bits_per_frame + 14).div_ceil(8)];
+            let total_frame_bytes = frame_bytes.len();
+            for ofs in 0..(total_frame_bytes * 8) {
+                let value = if ofs < 14 {
+                    // ECC
+                    mask
+                } else if ofs < 14 + bits_per_frame {
+                    get_bit(frame_index, ofs - 14)
+                } else {
+                    // padding, always one
+                    mask || ofs < 14 + bits_per_frame + ch.data.pad_bits_after_frame
+                };
+                if value {
+                    frame_bytes[(total_frame_bytes - 1) - (ofs / 8)] |= 1 << (ofs % 8);
+                }
+            }
+            self.write_bytes(&frame_bytes);
+        }
+    }
+
+    // Iterate over the commands in a bitstream, for disassembly and debugging
+    pub fn commands<'a>(&'a mut self, db: &'a mut Database) -> Result<BitstreamCommands<'a>, BitstreamError> {
+        match self.parse_container()? {
+            BitstreamType::NORMAL => Ok(BitstreamCommands {
+                parser: self,
+                db,
+                failed: false,
+            }),
+            BitstreamType::READBACK => Err(BitstreamError::UnsupportedFormat(
+                "readback images don't contain commands",
+            )),
+        }
+    }
+
+    // Metadata strings found in the bitstream header
+    pub fn get_metadata(&self) -> &[String] {
+        &self.metadata
+    }
+
+    pub fn parse(&mut self, db: &mut Database) -> Result<Chip, BitstreamError> {
         let typ = self.parse_container()?;
         let c = match typ {
             BitstreamType::NORMAL => self.parse_bitstream(db)?,
@@ -667,3 +1881,142 @@ impl BitstreamParser {
         Ok(c)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_database;
+    use crate::fasmparse::ParsedFasm;
+
+    #[test]
+    fn compressed_frame_roundtrip() {
+        let dict = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        // Lengths that do and don't need padding, covering zero, dictionary,
+        // one-hot and literal bytes
+        let frames: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00; 8],
+            vec![0x11, 0x88, 0x01, 0x80, 0xFF, 0x00, 0xA5],
+            (0..=255u8).collect(),
+            (0..37u8).map(|i| i.wrapping_mul(73)).collect(),
+        ];
+        for frame in frames.iter() {
+            let mut p = BitstreamParser::new(&[]);
+            p.write_compressed_frame(frame, &dict);
+            let written = p.data.len();
+            p.index = 0;
+            assert_eq!(&p.get_compressed_frame(&dict, frame.len()).unwrap(), frame);
+            assert_eq!(p.index, written);
+        }
+    }
+
+    #[test]
+    fn compressed_frame_encoding() {
+        // Frame of 6 bytes, encoded by hand from the format described at
+        // write_compressed_frame: 2 padding zeros, then 0x00 (0), dictionary
+        // entry 2 (100 010), one-hot 0x10 (101 100), literal 0xA5
+        // (11 10100101), dictionary entry 7 (100 111) and one-hot 0x01 (101 000)
+        let dict = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let frame = [0x00, 0x33, 0x10, 0xA5, 0x88, 0x01];
+        let encoded = [0x11, 0x59, 0xD2, 0xCF, 0x40];
+        let mut p = BitstreamParser::new(&encoded);
+        assert_eq!(p.get_compressed_frame(&dict, frame.len()).unwrap(), frame);
+        assert_eq!(p.index, encoded.len());
+        let mut p = BitstreamParser::new(&[]);
+        p.write_compressed_frame(&frame, &dict);
+        assert_eq!(p.data, encoded);
+    }
+
+    #[test]
+    fn bitstream_start_and_bit_reversal() {
+        let mut db = test_database();
+        let mut ch = Chip::from_name(&mut db, "TEST");
+        ch.metadata.push("test".to_string());
+        ch.cram.set(ch.frame_addr_to_idx(0x0003), 7, true);
+        for &(headerless, reverse_bits) in &[(false, false), (true, false), (false, true), (true, true)] {
+            ch.headerless = headerless;
+            ch.reverse_bits = reverse_bits;
+            let bs = BitstreamParser::serialise_chip(&ch);
+            // Flash images are preceded by erased (0xFF) padding
+            let mut padded = vec![0xFF; 16];
+            padded.extend_from_slice(&bs);
+            let pos = padded.iter().position(|&b| b != 0xFF).unwrap();
+            assert_eq!(BitstreamParser::find_bitstream_start(&padded, pos), Some(16));
+
+            let mut p = BitstreamParser::new(&bs);
+            let parsed = p.parse(&mut db).unwrap();
+            assert_eq!(p.is_bit_reversed(), reverse_bits);
+            assert_eq!(parsed.cram.delta(&ch.cram), vec![]);
+            assert_eq!(p.get_metadata().is_empty(), headerless);
+        }
+        assert_eq!(BitstreamParser::find_bitstream_start(&[0xFF, 0xFF, 0x12, 0x34], 2), None);
+    }
+
+    #[test]
+    fn lram_roundtrip() {
+        let mut db = test_database();
+        let roundtrip = |db: &mut Database, fasm: &str| {
+            let ch = Chip::try_from_fasm(db, &ParsedFasm::parse_str(fasm).unwrap(), Some("TEST")).unwrap();
+            let parsed = BitstreamParser::parse_bytes(db, &BitstreamParser::serialise_chip(&ch)).unwrap();
+            (ch, parsed)
+        };
+        // INITVAL_4B is past the 40960 byte chunk split
+        let (ch, parsed) = roundtrip(&mut db, "IP_LRAM_CORE_R18C86.INITVAL_05[15:0] = 16'h1234
+IP_LRAM_CORE_R18C86.INITVAL_4B[15:0] = 16'hBEEF
+");
+        assert_eq!(parsed.ipconfig, ch.ipconfig);
+        let decoded = parsed.decode_ip(&mut db);
+        let lram = &decoded.ips["LRAM_CORE_R18C86"];
+        let word = |v: u32| (0..16).map(|i| (v >> i) & 1 == 1).collect::<Vec<_>>();
+        assert_eq!(lram.words.keys().collect::<Vec<_>>(), vec!["INITVAL_05", "INITVAL_4B"]);
+        assert_eq!(lram.words["INITVAL_05"], word(0x1234));
+        assert_eq!(lram.words["INITVAL_4B"], word(0xBEEF));
+        assert!(decoded.unknown.is_empty());
+
+        // Lone bytes are written as whole frames, so come back at the same address
+        let (_ch, parsed) = roundtrip(&mut db, "IP_UNKNOWN.0x2E000007[7:0] = 8'h5A\n");
+        let expected: BTreeMap<u32, u8> = (0x2E000005..0x2E00000A).map(|a| (a, if a == 0x2E000007 { 0x5A } else { 0x00 })).collect();
+        assert_eq!(parsed.ipconfig, expected);
+    }
+
+    #[test]
+    fn partial_bitstream() {
+        let mut db = test_database();
+        let base = Chip::from_name(&mut db, "TEST");
+        // Frame writes as (address, count) and bus writes as (address, length)
+        let writes = |db: &mut Database, ch: &Chip| {
+            let bs = BitstreamParser::serialise_partial(ch, &base, db).unwrap();
+            let mut p = BitstreamParser::new(&bs);
+            let (mut frames, mut bus) = (Vec::new(), Vec::new());
+            for dc in p.commands(db).unwrap() {
+                match dc.unwrap().command {
+                    BitstreamCommand::ProgIncrRti { address, frames: f, .. } => frames.push((address, f.len())),
+                    BitstreamCommand::BusWrite { address, data, .. } => bus.push((address, data.len())),
+                    _ => {}
+                }
+            }
+            (frames, bus)
+        };
+        assert_eq!(writes(&mut db, &base), (vec![], vec![]));
+
+        let mut ch = base.clone();
+        ch.cram.set(ch.frame_addr_to_idx(0x0003), 7, true);
+        assert_eq!(writes(&mut db, &ch), (vec![(0x0003, 1)], vec![]));
+
+        // Bus writes are of whole 5 byte EBR frames, counted from the base of each EBR
+        let mut ch = base.clone();
+        ch.ipconfig.insert(0x20000007, 0x5A);
+        ch.ipconfig.insert(0x20001002, 0xA5);
+        assert_eq!(writes(&mut db, &ch), (vec![], vec![(0x20000005, 5), (0x20001000, 5)]));
+
+        let mut other = base.clone();
+        other.device = "OTHER".to_string();
+        assert_eq!(
+            BitstreamParser::serialise_partial(&other, &base, &mut db).err(),
+            Some(DeviceMismatch {
+                expected: "OTHER".to_string(),
+                found: "TEST".to_string(),
+            })
+        );
+    }
+}
@@ -2,91 +2,419 @@ use crate::database::*;
 use crate::fasmparse::*;
 use crate::bels::*;
 use multimap::MultiMap;
+use rug::Integer;
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::io::Write;
 
 use log::*;
 
 // 2D bit array
-#[derive(Clone)]
+//
+// Each frame is stored as a row of 64-bit words, bit `b` of a frame being bit
+// `b % 64` of word `b / 64`. Unused bits at the end of a row are always zero.
+#[derive(Clone, PartialEq, Eq)]
 pub struct BitMatrix {
     pub frames: usize,
     pub bits: usize,
-    data: Vec<bool>,
+    stride: usize,
+    data: Vec<u64>,
 }
 
+const WORD_BITS: usize = 64;
+
 impl BitMatrix {
     // Create new empty bitmatrix
     pub fn new(frames: usize, bits: usize) -> BitMatrix {
+        let stride = bits.div_ceil(WORD_BITS);
         BitMatrix {
             frames: frames,
             bits: bits,
-            data: vec![false; frames * bits],
+            stride,
+            data: vec![0; frames * stride],
         }
     }
     // Getting and setting bits
     pub fn get(&self, frame: usize, bit: usize) -> bool {
-        self.data[frame * self.bits + bit]
+        assert!(bit < self.bits);
+        (self.data[frame * self.stride + bit / WORD_BITS] >> (bit % WORD_BITS)) & 1 == 1
     }
     pub fn set(&mut self, frame: usize, bit: usize, val: bool) {
-        self.data[frame * self.bits + bit] = val
+        assert!(bit < self.bits);
+        let word = &mut self.data[frame * self.stride + bit / WORD_BITS];
+        let mask = 1u64 << (bit % WORD_BITS);
+        if val {
+            *word |= mask;
+        } else {
+            *word &= !mask;
+        }
+    }
+    // Words of a single frame
+    fn row(&self, frame: usize) -> &[u64] {
+        &self.data[frame * self.stride..(frame + 1) * self.stride]
+    }
+    // Read up to 64 bits of a frame starting at `start`
+    fn read_bits(&self, frame: usize, start: usize, len: usize) -> u64 {
+        let row = self.row(frame);
+        let (w, s) = (start / WORD_BITS, start % WORD_BITS);
+        let mut val = row[w] >> s;
+        if s != 0 && s + len > WORD_BITS {
+            val |= row[w + 1] << (WORD_BITS - s);
+        }
+        val & low_mask(len)
+    }
+    // Write up to 64 bits of a frame starting at `start`
+    fn write_bits(&mut self, frame: usize, start: usize, len: usize, val: u64) {
+        let base = frame * self.stride;
+        let (w, s) = (start / WORD_BITS, start % WORD_BITS);
+        let mask = low_mask(len);
+        let val = val & mask;
+        self.data[base + w] = (self.data[base + w] & !(mask << s)) | (val << s);
+        if s != 0 && s + len > WORD_BITS {
+            let hi = WORD_BITS - s;
+            self.data[base + w + 1] = (self.data[base + w + 1] & !(mask >> hi)) | (val >> hi);
+        }
+    }
+    // Copy `len` bits from a frame of another bitmatrix to a frame of this one
+    fn copy_bits(&mut self, frame: usize, bit: usize, from: &Self, from_frame: usize, from_bit: usize, len: usize) {
+        let mut done = 0;
+        while done < len {
+            let n = std::cmp::min(WORD_BITS, len - done);
+            let val = from.read_bits(from_frame, from_bit + done, n);
+            self.write_bits(frame, bit + done, n, val);
+            done += n;
+        }
     }
     // Copy another bitmatrix to a window of this one
     pub fn copy_window(&mut self, from: &Self, start_frame: usize, start_bit: usize) {
+        assert!(start_frame + from.frames <= self.frames && start_bit + from.bits <= self.bits);
         for f in 0..from.frames {
-            for b in 0..from.bits {
-                self.data[(f + start_frame) * self.bits + (b + start_bit)] =
-                    from.data[f * from.bits + b];
-            }
+            self.copy_bits(f + start_frame, start_bit, from, f, 0, from.bits);
         }
     }
     // Copy a window another bitmatrix  to this one
     pub fn copy_from_window(&mut self, from: &Self, start_frame: usize, start_bit: usize) {
+        assert!(start_frame + self.frames <= from.frames && start_bit + self.bits <= from.bits);
         for f in 0..self.frames {
-            for b in 0..self.bits {
-                self.data[f * self.bits + b] =
-                    from.data[(f + start_frame) * from.bits + (b + start_bit)];
-            }
+            self.copy_bits(f, 0, from, f + start_frame, start_bit, self.bits);
         }
     }
+    // Iterate over the positions of set bits in a sequence of frame rows
+    fn iter_ones<'a>(&'a self, words: impl Iterator<Item = u64> + 'a) -> impl Iterator<Item = (usize, usize)> + 'a {
+        let stride = self.stride;
+        words.enumerate().flat_map(move |(i, mut w)| {
+            let (f, b0) = (i / stride, (i % stride) * WORD_BITS);
+            std::iter::from_fn(move || {
+                if w == 0 {
+                    return None;
+                }
+                let b = w.trailing_zeros() as usize;
+                w &= w - 1;
+                Some((f, b0 + b))
+            })
+        })
+    }
     // Get a list of the differences
     // as a tuple (frame, bit, new value)
     pub fn delta(&self, base: &Self) -> Vec<(usize, usize, bool)> {
-        base.data
-            .iter()
-            .zip(self.data.iter())
-            .enumerate()
-            .filter_map(|(i, (o, n))| {
-                let f = i / self.bits;
-                let b = i % self.bits;
-                match (o, n) {
-                    (false, true) => Some((f, b, true)),  // going high
-                    (true, false) => Some((f, b, false)), // going low
-                    _ => None,
-                }
-            })
+        assert!(self.frames == base.frames && self.bits == base.bits);
+        self.iter_ones(base.data.iter().zip(self.data.iter()).map(|(o, n)| o ^ n))
+            .map(|(f, b)| (f, b, self.get(f, b)))
             .collect()
     }
     // Pretty-print a list of frame-bits
     pub fn print(&self, mut out: &mut dyn Write) {
-        for (i, _x) in self.data.iter().enumerate().filter(|(_i, x)| **x) {
-            let f = i / self.bits;
-            let b = i % self.bits;
+        for (f, b) in self.iter_ones(self.data.iter().copied()) {
             writeln!(&mut out, "F{}B{}", f, b).unwrap();
         }
     }
     // Return true if any bit is set
     pub fn any(&self) -> bool {
-        return self.data.iter().any(|x| *x);
+        self.data.iter().any(|x| *x != 0)
+    }
+    // Return the number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.data.iter().map(|x| x.count_ones() as usize).sum()
     }
     // Get all set bits
     pub fn set_bits(&self) -> BTreeSet<(usize, usize)> {
-        self.data
-            .iter()
-            .enumerate()
-            .filter(|(_i, x)| **x)
-            .map(|(i, _x)| (i / self.bits, i % self.bits))
-            .collect()
+        self.iter_ones(self.data.iter().copied()).collect()
+    }
+}
+
+// Mask of the lowest `len` bits, for `len` up to 64
+fn low_mask(len: usize) -> u64 {
+    if len >= WORD_BITS {
+        !0
+    } else {
+        (1u64 << len) - 1
+    }
+}
+
+const SED_CRC_POLY: u32 = 0x04C11DB7;
+const SED_CRC_INIT: u32 = 0xFFFFFFFF;
+
+// A chip-level CRAM bit, located within a tile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileBit {
+    pub tile: String,
+    pub tiletype: String,
+    // Tile-relative position
+    pub frame: usize,
+    pub bit: usize,
+    // Features in the tile's database using this bit
+    pub features: Vec<BitFeature>,
+}
+
+// A CRAM bit that doesn't have the expected value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BitMismatch {
+    pub frame: usize,
+    pub bit: usize,
+    pub expected: bool,
+    // Tiles covering this bit; there may be more than one as tiles can overlap
+    pub tiles: Vec<TileBit>,
+}
+
+// Two chips that have to be of the same device aren't
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceMismatch {
+    pub expected: String,
+    pub found: String,
+}
+
+impl DeviceMismatch {
+    // Check that `found` is of the same device as `expected`
+    pub fn check(expected: &Chip, found: &Chip) -> Result<(), DeviceMismatch> {
+        if expected.device == found.device {
+            Ok(())
+        } else {
+            Err(DeviceMismatch {
+                expected: expected.device.to_string(),
+                found: found.device.to_string(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for DeviceMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected a {} configuration, found {}", self.expected, self.found)
+    }
+}
+
+impl std::error::Error for DeviceMismatch {}
+
+// Reasons a FASM feature or attribute couldn't be applied to a chip
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FasmConfigErrorKind {
+    // No oxide.device attribute, and no device given
+    NoDevice,
+    UnknownDevice(String),
+    InvalidAttribute { key: String, value: String },
+    // Neither a tile nor a tilegroup
+    UnknownTile,
+    UnknownIP,
+    UnknownEnum(String),
+    UnknownOption {
+        name: String,
+        option: String,
+        valid: Vec<String>,
+    },
+    UnknownWord(String),
+    WordTooWide {
+        name: String,
+        width: usize,
+        max: usize,
+    },
+    UnknownPip { to_wire: String, from_wire: String },
+    // An UNKNOWN.frame.bit feature outside the tile
+    InvalidBit { frame: usize, bit: usize },
+    // A pip or unknown bit given for a tilegroup, rather than a single tile
+    NotForTileGroup(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FasmConfigError {
+    // FASM source line, if known
+    pub line: Option<usize>,
+    // Tile, tilegroup or IP (with the IP_ prefix) containing the feature;
+    // empty for attributes
+    pub tile: String,
+    pub kind: FasmConfigErrorKind,
+    // Closest valid name in the database, in FASM form
+    pub suggestion: Option<String>,
+}
+
+impl FasmConfigError {
+    fn new(line: usize, tile: &str, kind: FasmConfigErrorKind) -> FasmConfigError {
+        FasmConfigError {
+            line: if line > 0 { Some(line) } else { None },
+            tile: tile.to_string(),
+            kind,
+            suggestion: None,
+        }
+    }
+    fn suggest<'a>(mut self, candidates: impl Iterator<Item = &'a str>) -> FasmConfigError {
+        let name = match &self.kind {
+            FasmConfigErrorKind::UnknownDevice(n) => n.to_string(),
+            FasmConfigErrorKind::UnknownTile | FasmConfigErrorKind::UnknownIP => self.tile.to_string(),
+            FasmConfigErrorKind::UnknownEnum(n) | FasmConfigErrorKind::UnknownWord(n) => n.to_string(),
+            FasmConfigErrorKind::UnknownOption { option, .. } => option.to_string(),
+            FasmConfigErrorKind::UnknownPip { to_wire, from_wire } => format!("PIP.{}.{}", to_wire, from_wire),
+            _ => return self,
+        };
+        self.suggestion = closest_name(&name, candidates).map(|s| s.replace(':', "__"));
+        self
+    }
+}
+
+impl fmt::Display for FasmConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        let tile = self.tile.replace(':', "__");
+        match &self.kind {
+            FasmConfigErrorKind::NoDevice => write!(f, "no device specified")?,
+            FasmConfigErrorKind::UnknownDevice(d) => write!(f, "no device in database with name {}", d)?,
+            FasmConfigErrorKind::InvalidAttribute { key, value } => {
+                write!(f, "invalid value {} for {}", value, key)?
+            }
+            FasmConfigErrorKind::UnknownTile => write!(f, "no tile or tilegroup named {}", tile)?,
+            FasmConfigErrorKind::UnknownIP => write!(f, "no IP named {}", tile)?,
+            FasmConfigErrorKind::UnknownEnum(n) => write!(f, "no enum named {} in {}", n, tile)?,
+            FasmConfigErrorKind::UnknownOption { name, option, valid } => write!(
+                f,
+                "no option named {} for enum {} in {} (valid options are: {})",
+                option,
+                name,
+                tile,
+                valid.join(", ")
+            )?,
+            FasmConfigErrorKind::UnknownWord(n) => write!(f, "no word named {} in {}", n, tile)?,
+            FasmConfigErrorKind::WordTooWide { name, width, max } => write!(
+                f,
+                "word {} in {} has value width {} exceeding database width of {}",
+                name, tile, width, max
+            )?,
+            FasmConfigErrorKind::UnknownPip { to_wire, from_wire } => write!(
+                f,
+                "no pip PIP.{}.{} in {}",
+                to_wire.replace(':', "__"),
+                from_wire.replace(':', "__"),
+                tile
+            )?,
+            FasmConfigErrorKind::InvalidBit { frame, bit } => {
+                write!(f, "bit F{}B{} is outside {}", frame, bit, tile)?
+            }
+            FasmConfigErrorKind::NotForTileGroup(feature) => write!(
+                f,
+                "{} can't be set for tilegroup {}, only for a single tile",
+                feature.replace(':', "__"),
+                tile
+            )?,
+        }
+        if let Some(s) = &self.suggestion {
+            write!(f, " (did you mean {}?)", s)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FasmConfigError {}
+
+// Panic with a list of errors from applying FASM
+fn panic_on_fasm_errors(errors: &[FasmConfigError]) -> ! {
+    panic!(
+        "{}\n\
+Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.",
+        errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("\n")
+    );
+}
+
+// Find the candidate closest to a misspelt name, if any is reasonably close
+fn closest_name<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<String> {
+    let max_dist = std::cmp::max(2, name.len() / 3);
+    candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|&(d, _c)| d <= max_dist)
+        .min()
+        .map(|(_d, c)| c.to_string())
+}
+
+// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = std::cmp::min(subst, std::cmp::min(prev[j + 1], curr[j]) + 1);
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+// Configuration control settings, programmed using the CTRL0 register
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigControl {
+    // Background programming support; this is a group of bits that Radiant
+    // always sets together, their individual functions aren't known
+    pub background: bool,
+    // Any other bits, which don't have a known function but are preserved
+    pub unknown: u32,
+}
+
+impl ConfigControl {
+    const BACKGROUND_BITS: u32 = 0x27800000;
+
+    // Decode a CTRL0 value
+    pub fn from_ctrl0(ctrl0: u32) -> ConfigControl {
+        let background = (ctrl0 & ConfigControl::BACKGROUND_BITS) == ConfigControl::BACKGROUND_BITS;
+        ConfigControl {
+            background,
+            unknown: if background {
+                ctrl0 & !ConfigControl::BACKGROUND_BITS
+            } else {
+                ctrl0
+            },
+        }
+    }
+    // Encode to a CTRL0 value
+    pub fn to_ctrl0(&self) -> u32 {
+        let mut ctrl0 = self.unknown;
+        if self.background {
+            ctrl0 |= ConfigControl::BACKGROUND_BITS;
+        }
+        ctrl0
+    }
+}
+
+// Security setting, programmed using ISC_PROGRAM_SECURITY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SecurityMode {
+    // Configuration can be read back
+    Open,
+    // Configuration readback is disabled
+    ReadbackDisabled,
+}
+
+impl SecurityMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SecurityMode::Open => "OPEN",
+            SecurityMode::ReadbackDisabled => "READBACK_DISABLED",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<SecurityMode> {
+        match name {
+            "OPEN" => Some(SecurityMode::Open),
+            "READBACK_DISABLED" => Some(SecurityMode::ReadbackDisabled),
+            _ => None,
+        }
     }
 }
 
@@ -115,10 +443,45 @@ pub struct Chip {
     pub metadata: Vec<String>,
     // Extra bitstream settings
     pub settings: BTreeMap<String, String>,
+    // USERCODE programmed by the bitstream
+    pub usercode: u32,
+    // Configuration control (CTRL0) settings
+    pub ctrl0: ConfigControl,
+    // Security (readback protection) setting
+    pub security: SecurityMode,
+    // The bitstream included the SED CRC for soft error detection; this isn't
+    // written when packing (see Chip::compute_sed_crc)
+    pub sed_crc: bool,
+    // Compress frame data using a dictionary
+    pub compress: bool,
+    // Omit the "LSCC" magic and metadata, for raw flash images
+    pub headerless: bool,
+    // Bit reverse every byte, for slave SPI shifted out LSB first
+    pub reverse_bits: bool,
     // number of TAP frames
     pub tap_frame_count: usize,
 }
 
+// Canonical form of FASM: the configuration it describes, written out as
+// unpack would. Tilegroups are resolved into the tiles they configure, word
+// slices are merged and features are sorted, so that equivalent FASM from
+// different tools gives identical output
+pub fn canonicalise_fasm(db: &mut Database, fasm: &ParsedFasm, device: Option<&str>) -> Result<String, Vec<FasmConfigError>> {
+    let chip = Chip::try_from_fasm(db, fasm, device)?;
+    let mut out = Vec::new();
+    chip.write_fasm(db, &mut out, false).unwrap();
+    Ok(String::from_utf8(out).unwrap())
+}
+
+// Parse a hex (0x prefixed) or decimal setting value
+pub fn parse_u32_value(value: &str) -> Option<u32> {
+    if value.starts_with("0x") || value.starts_with("0X") {
+        u32::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse::<u32>().ok()
+    }
+}
+
 pub type ChipDelta = BTreeMap<String, Vec<(usize, usize, bool)>>;
 // address, bit, new value
 pub type IPDelta = Vec<(u32, u8, bool)>;
@@ -142,6 +505,13 @@ impl Chip {
             tilegroups: HashMap::new(),
             metadata: Vec::new(),
             settings: BTreeMap::new(),
+            usercode: 0x00000000,
+            ctrl0: ConfigControl::default(),
+            security: SecurityMode::Open,
+            sed_crc: false,
+            compress: false,
+            headerless: false,
+            reverse_bits: false,
             tap_frame_count: match device {
                 "LFCPNX-100" => 42,
                 _ => 24,
@@ -163,71 +533,230 @@ impl Chip {
     }
     // Create a new chip from the database based on IDCODE or name
     pub fn from_idcode(db: &mut Database, idcode: u32) -> Chip {
-        let (fam, device, variant, data) = db.device_by_idcode(idcode).expect(&format!(
-            "no device in database with IDCODE {:08x}\n",
-            idcode
-        ));
-        Chip::new(&fam, &device, &variant, &data, db.device_tilegrid(&fam, &device))
+        Chip::try_from_idcode(db, idcode)
+            .unwrap_or_else(|| panic!("no device in database with IDCODE {:08x}\n", idcode))
+    }
+    // As above, but returning None rather than panicking for an unknown IDCODE
+    pub fn try_from_idcode(db: &mut Database, idcode: u32) -> Option<Chip> {
+        let (fam, device, variant, data) = db.device_by_idcode(idcode)?;
+        Some(Chip::new(&fam, &device, &variant, &data, db.device_tilegrid(&fam, &device)))
     }
     pub fn from_name(db: &mut Database, name: &str) -> Chip {
         let (fam, device, data) = db
             .device_by_name(name)
-            .expect(&format!("no device in database with name {}\n", name));
+            .unwrap_or_else(|| panic!("no device in database with name {}\n", name));
         Chip::new(&fam, &device, "", &data, db.device_tilegrid(&fam, &device))
     }
     pub fn from_name_variant(db: &mut Database, name: &str, variant: &str) -> Chip {
         let (fam, device, data) = db
             .device_by_name(name)
-            .expect(&format!("no device in database with name {}\n", name));
+            .unwrap_or_else(|| panic!("no device in database with name {}\n", name));
         Chip::new(&fam, &device, variant, &data, db.device_tilegrid(&fam, &device))
     }
     pub fn from_fasm(db: &mut Database, fasm: &ParsedFasm, device: Option<&str>) -> Chip {
-        let mut chip = match device {
-            Some(d) => Chip::from_name(db, d),
+        Chip::try_from_fasm(db, fasm, device).unwrap_or_else(|errors| panic_on_fasm_errors(&errors))
+    }
+    // As above, but returning every problem found rather than panicking on the first
+    pub fn try_from_fasm(db: &mut Database, fasm: &ParsedFasm, device: Option<&str>) -> Result<Chip, Vec<FasmConfigError>> {
+        let attr_line = |key: &str| {
+            fasm.attrs
+                .iter()
+                .zip(fasm.attr_spans.iter())
+                .find(|((k, _), _)| k == key)
+                .map_or(0, |(_, s)| s.line)
+        };
+        let default_variant = "".to_string();
+        let (name, variant) = match device {
+            Some(d) => (d.to_string(), &default_variant),
             None => {
                 let name = &fasm
                     .attrs
                     .iter()
                     .find(|(k, _)| k == "oxide.device")
-                    .unwrap()
+                    .ok_or_else(|| vec![FasmConfigError::new(0, "", FasmConfigErrorKind::NoDevice)])?
                     .1;
-                let default_variant = ("".to_string(), "".to_string());
-                let variant = &fasm
+                let variant = fasm
                     .attrs
                     .iter()
                     .find(|(k, _)| k == "oxide.device_variant")
-                    .unwrap_or(&default_variant)
-                    .1;
-                Chip::from_name_variant(db, name, variant)
+                    .map_or(&default_variant, |(_, v)| v);
+                (name.to_string(), variant)
             }
         };
+        if db.device_by_name(&name).is_none() {
+            let names = db.device_names();
+            return Err(vec![FasmConfigError::new(
+                attr_line("oxide.device"),
+                "",
+                FasmConfigErrorKind::UnknownDevice(name),
+            )
+            .suggest(names.iter().map(|n| n.as_str()))]);
+        }
+        let mut chip = Chip::from_name_variant(db, &name, variant);
         chip.create_tilegroups(db);
-        chip.metadata.extend(
+        for t in chip.tiles.iter_mut() {
+            let tdb = db.tile_bitdb(&chip.family, &t.tiletype);
+            for aon in tdb.db.always_on.iter() {
+                t.cram.set(aon.frame, aon.bit, true);
+            }
+        }
+        chip.apply_fasm_features(db, fasm, false)?;
+        chip.tiles_to_cram();
+        Ok(chip)
+    }
+    // Apply FASM on top of the existing configuration, e.g. to patch a bitstream.
+    // Setting an enum or pip replaces the current option of that enum or the
+    // current source of that pip's sink wire; features set to 0 are cleared.
+    // The chip is left unchanged if there are any errors
+    pub fn apply_fasm(&mut self, db: &mut Database, fasm: &ParsedFasm) {
+        self.try_apply_fasm(db, fasm).unwrap_or_else(|errors| panic_on_fasm_errors(&errors))
+    }
+    pub fn try_apply_fasm(&mut self, db: &mut Database, fasm: &ParsedFasm) -> Result<(), Vec<FasmConfigError>> {
+        let mut chip = self.clone();
+        if chip.tilegroups.is_empty() {
+            chip.create_tilegroups(db);
+        }
+        chip.cram_to_tiles();
+        chip.apply_fasm_features(db, fasm, true)?;
+        chip.tiles_to_cram();
+        *self = chip;
+        Ok(())
+    }
+    // Write the configuration as FASM, as produced by unpack. Tiles must be up
+    // to date with the CRAM (see Chip::cram_to_tiles)
+    pub fn write_fasm(&self, db: &mut Database, out: &mut dyn Write, annotate: bool) -> std::io::Result<()> {
+        writeln!(out, "{{ oxide.device=\"{}\" }}", self.device)?;
+        writeln!(out, "{{ oxide.device_variant=\"{}\" }}", self.variant)?;
+        writeln!(out, "{{ oxide.usercode=\"0x{:08X}\" }}", self.usercode)?;
+        if self.ctrl0.background {
+            writeln!(out, "{{ oxide.ctrl0.background=\"1\" }}")?;
+        }
+        if self.ctrl0.unknown != 0 {
+            writeln!(out, "{{ oxide.ctrl0.unknown=\"0x{:08X}\" }}", self.ctrl0.unknown)?;
+        }
+        if self.sed_crc {
+            writeln!(out, "{{ oxide.sed_crc=\"1\" }}")?;
+        }
+        if self.security != SecurityMode::Open {
+            writeln!(out, "{{ oxide.security=\"{}\" }}", self.security.name())?;
+        }
+        writeln!(out)?;
+
+        for metadata in self.metadata.iter() {
+            writeln!(out, "{{ oxide.meta=\"{}\" }}", metadata)?;
+        }
+        if !self.metadata.is_empty() {
+            writeln!(out)?;
+        }
+
+        for tile in self.tiles.iter() {
+            if annotate {
+                tile.write_annotated_fasm(db, out);
+            } else {
+                tile.write_fasm(db, out);
+            }
+        }
+
+        let decoded = self.decode_ip(db);
+        for (ip, config) in decoded.ips.iter() {
+            let desc = |d: &str| if annotate && !d.is_empty() { format!(" # {}", d) } else { String::new() };
+            if annotate {
+                let base = self.try_get_ip_baseaddr(db, ip).unwrap();
+                writeln!(out, "# IP_{} ({}) at 0x{:08X}", ip, self.get_ip_type(ip), base)?;
+            }
+            let tdb = &db.ip_bitdb(&self.family, self.get_ip_type(ip)).db;
+            for (name, opt) in config.enums.iter() {
+                let d = tdb.enums.get(name).map_or("", |e| e.desc.as_str());
+                writeln!(out, "IP_{}.{}.{}{}", ip, name, opt, desc(d))?;
+            }
+            for (name, value) in config.words.iter() {
+                let d = tdb.words.get(name).map_or("", |w| w.desc.as_str());
+                writeln!(out, "IP_{}.{}[{}:0] = {}{}", ip, name, value.len() - 1, format_word_hex(value), desc(d))?;
+            }
+            writeln!(out)?;
+        }
+
+        for (addr, val) in decoded.unknown.iter() {
+            writeln!(out, "IP_UNKNOWN.0x{:08X}[7:0] = 8'h{:02X};", addr, val)?;
+        }
+        Ok(())
+    }
+    // Apply attributes and features from FASM to the chip's tiles and IP config;
+    // if `replace` is set, clear the existing setting of each enum and pip sink first
+    fn apply_fasm_features(&mut self, db: &mut Database, fasm: &ParsedFasm, replace: bool) -> Result<(), Vec<FasmConfigError>> {
+        let mut errors = Vec::new();
+        self.metadata.extend(
             fasm.attrs
                 .iter()
                 .filter_map(|(k, v)| if k == "oxide.meta" { Some(v) } else { None })
                 .cloned(),
         );
-        for t in chip.tiles.iter_mut() {
-            let tdb = db.tile_bitdb(&chip.family, &t.tiletype);
-            for aon in tdb.db.always_on.iter() {
-                t.cram.set(aon.frame, aon.bit, true);
+        for ((k, v), span) in fasm.attrs.iter().zip(fasm.attr_spans.iter()) {
+            let line = span.line;
+            let invalid = || FasmConfigError::new(line, "", FasmConfigErrorKind::InvalidAttribute {
+                key: k.to_string(),
+                value: v.to_string(),
+            });
+            let value = parse_u32_value(v);
+            match (k.as_str(), value) {
+                ("oxide.usercode", Some(x)) => self.usercode = x,
+                ("oxide.ctrl0", Some(x)) => self.ctrl0 = ConfigControl::from_ctrl0(x),
+                ("oxide.ctrl0.background", Some(x)) => self.ctrl0.background = x != 0,
+                ("oxide.ctrl0.unknown", Some(x)) => self.ctrl0.unknown = x,
+                ("oxide.sed_crc", Some(x)) => self.sed_crc = x != 0,
+                ("oxide.usercode", None)
+                | ("oxide.ctrl0", None)
+                | ("oxide.ctrl0.background", None)
+                | ("oxide.ctrl0.unknown", None)
+                | ("oxide.sed_crc", None) => errors.push(invalid()),
+                ("oxide.security", _) => match SecurityMode::from_name(v) {
+                    Some(s) => self.security = s,
+                    None => errors.push(invalid()),
+                },
+                _ => {}
             }
         }
-        for (tn, ft) in fasm.tiles.iter() {
+        // Raw IP bytes are applied last, whatever the order of the FASM, so that
+        // they override the named IP features that they overlap
+        let (unknown_ip, tiles): (Vec<_>, Vec<_>) = fasm.tiles.iter().partition(|(tn, _ft)| tn.as_str() == "IP_UNKNOWN");
+        for (tn, ft) in tiles.into_iter().chain(unknown_ip) {
             // Might be a tilegroup or single tile
-            if tn.starts_with("IP_") {
+            let result = if let Some(ip_name) = tn.strip_prefix("IP_") {
                 // IP configuration space
-                let ip_name = &tn[3..];
-                chip.configure_ip(ip_name, db, ft);
-            } else if chip.tilegroups.contains_key(tn) {
-                chip.apply_tilegroup(tn, db, ft);
+                if replace {
+                    self.clear_ip_enums(ip_name, db, ft);
+                }
+                self.try_configure_ip(ip_name, db, ft)
+            } else if self.tilegroups.contains_key(tn) {
+                if replace {
+                    for t in self.tilegroups.get(tn).unwrap().clone() {
+                        self.tile_by_name_mut(&t).unwrap().clear_replaced(db, ft);
+                    }
+                }
+                self.try_apply_tilegroup(tn, db, ft)
+            } else if let Some(&i) = self.tiles_by_name.get(tn) {
+                if replace {
+                    self.tiles[i].clear_replaced(db, ft);
+                }
+                self.tiles[i].try_from_fasm(db, ft)
             } else {
-                chip.tile_by_name_mut(tn).unwrap().from_fasm(db, ft);
+                let err = FasmConfigError::new(ft.span.line, tn, FasmConfigErrorKind::UnknownTile).suggest(
+                    self.tiles
+                        .iter()
+                        .map(|t| t.name.as_str())
+                        .chain(self.tilegroups.keys().map(|k| k.as_str())),
+                );
+                Err(vec![err])
+            };
+            if let Err(mut e) = result {
+                errors.append(&mut e);
             }
         }
-        chip.tiles_to_cram();
-        return chip;
+        if !errors.is_empty() {
+            errors.sort_by_key(|e| e.line);
+            return Err(errors);
+        }
+        Ok(())
     }
     // Copy the whole-chip CRAM to the per-tile CRAM
     pub fn cram_to_tiles(&mut self) {
@@ -322,63 +851,186 @@ impl Chip {
     }
     // Convert frame address to flat frame index
     pub fn frame_addr_to_idx(&self, addr: u32) -> usize {
+        self.try_frame_addr_to_idx(addr)
+            .unwrap_or_else(|| panic!("unable to process frame address 0x{:08x}", addr))
+    }
+    // As above, but returning None for addresses that don't map to a frame of this device
+    pub fn try_frame_addr_to_idx(&self, addr: u32) -> Option<usize> {
         match addr {
-            0x0000..=0x7FFF => (self.cram.frames - 1) - (addr as usize),
-            0x8000..=0x800F => (15 - ((addr - 0x8000) as usize)) + (16 + self.tap_frame_count), // right side IO
-            0x8010..=0x801F => (15 - ((addr - 0x8010) as usize)) + 0,  // left side IO
-            0x8020..=0x81FF => ((self.tap_frame_count - 1) - ((addr - 0x8020) as usize)) + 16, // TAPs (row-segment clocking)
-            _ => panic!("unable to process frame address 0x{:08x}", addr),
+            0x0000..=0x7FFF => (self.cram.frames - 1).checked_sub(addr as usize),
+            0x8000..=0x800F => Some((15 - ((addr - 0x8000) as usize)) + (16 + self.tap_frame_count)), // right side IO
+            0x8010..=0x801F => Some(15 - ((addr - 0x8010) as usize)), // left side IO
+            0x8020..=0x81FF => (self.tap_frame_count - 1).checked_sub((addr - 0x8020) as usize).map(|x| x + 16), // TAPs (row-segment clocking)
+            _ => None,
         }
     }
     // Get the frame size in bytes for bus regions
     pub fn get_bus_frame_size(&self, addr: u32) -> usize {
+        self.try_get_bus_frame_size(addr).unwrap_or_else(|| panic!(
+            "unable to determine frame size of bus address 0x{:08x}",
+            addr
+        ))
+    }
+    // As above, but returning None for addresses outside of the known bus regions
+    pub fn try_get_bus_frame_size(&self, addr: u32) -> Option<usize> {
         match (addr & 0xF0000000) >> 28 {
-            0 => 1, // non-PCIe IP cores
-            2 => 5, // BRAM and LRAM
-            3 => 4, // PCIe IP
-            _ => panic!(
-                "unable to determine frame size of bus address 0x{:08x}",
-                addr
-            ),
+            0 => Some(1), // non-PCIe IP cores
+            2 => Some(5), // BRAM and LRAM
+            3 => Some(4), // PCIe IP
+            _ => None,
         }
     }
     // Convert a long package name to a short one
     pub fn get_package_short_name(long_name: &str) -> String {
-        if long_name.starts_with("CABGA") {
-            format!("BG{}", &long_name[5..])
-        } else if long_name.starts_with("CSBGA") {
-            format!("MG{}", &long_name[5..])
-        } else if long_name.starts_with("CSFBGA") {
-            format!("MG{}", &long_name[6..])
-        } else if long_name.starts_with("QFN") {
-            format!("SG{}", &long_name[3..])
-        } else if long_name.starts_with("WLCSP") {
-            format!("UWG{}", &long_name[5..])
+        if let Some(pins) = long_name.strip_prefix("CABGA") {
+            format!("BG{}", pins)
+        } else if let Some(pins) = long_name.strip_prefix("CSBGA") {
+            format!("MG{}", pins)
+        } else if let Some(pins) = long_name.strip_prefix("CSFBGA") {
+            format!("MG{}", pins)
+        } else if let Some(pins) = long_name.strip_prefix("QFN") {
+            format!("SG{}", pins)
+        } else if let Some(pins) = long_name.strip_prefix("WLCSP") {
+            format!("UWG{}", pins)
         } else {
             panic!("unknown package name {}", &long_name);
         }
     }
     // Get the base address for an IP
     pub fn get_ip_baseaddr(&self, db: &mut Database, ip: &str) -> u32 {
+        self.try_get_ip_baseaddr(db, ip).unwrap_or_else(|| panic!("no IP named {}", ip))
+    }
+    pub fn try_get_ip_baseaddr(&self, db: &mut Database, ip: &str) -> Option<u32> {
         let baseaddrs = db.device_baseaddrs(&self.family, &self.device);
-        if ip.starts_with("EBR_WID") {
+        if let Some(wid) = ip.strip_prefix("EBR_WID") {
             // Special case as we don't want to fill up the DB with 2048 entries
-            let base = baseaddrs.regions.get("EBR_WID0").unwrap().addr;
-            let offset = baseaddrs.regions.get("EBR_WID1").unwrap().addr - base;
-            let wid = ip[7..].parse::<u32>().unwrap();
-            return base + wid * offset;
+            let base = baseaddrs.regions.get("EBR_WID0")?.addr;
+            let offset = baseaddrs.regions.get("EBR_WID1")?.addr - base;
+            let wid = wid.parse::<u32>().ok()?;
+            Some(base + wid * offset)
         } else {
-            return baseaddrs.regions.get(ip).unwrap_or_else(|| panic!("no IP named {}", ip)).addr;
+            baseaddrs.regions.get(ip).map(|r| r.addr)
+        }
+    }
+    // Find the IP containing an address in IP config space, as (name, base address)
+    pub fn ip_at_address(&self, db: &mut Database, addr: u32) -> Option<(String, u32)> {
+        let baseaddrs = db.device_baseaddrs(&self.family, &self.device);
+        if let (Some(wid0), Some(wid1)) = (baseaddrs.regions.get("EBR_WID0"), baseaddrs.regions.get("EBR_WID1")) {
+            let offset = wid1.addr - wid0.addr;
+            if addr >= wid0.addr && addr - wid0.addr < offset * 2048 {
+                let wid = (addr - wid0.addr) / offset;
+                return Some((format!("EBR_WID{}", wid), wid0.addr + wid * offset));
+            }
         }
+        baseaddrs
+            .regions
+            .iter()
+            .find(|(_k, r)| addr >= r.addr && ((addr - r.addr) >> r.abits) == 0)
+            .map(|(k, r)| (k.to_string(), r.addr))
+    }
+    // Gets an IP bit; unset bytes read as zero
+    pub fn get_ip_bit(&self, offset: u32, word: u32, bit: u32) -> bool {
+        self.ipconfig.get(&(offset + word)).is_some_and(|b| (b >> bit) & 1 == 1)
+    }
+    // Decode IP configuration space into the features of each IP, as the
+    // reverse of configure_ip. Features are only given where packing them
+    // reproduces the configuration exactly; anything else is left as raw bytes
+    pub fn decode_ip(&self, db: &mut Database) -> DecodedIP {
+        let mut ips = BTreeMap::<String, u32>::new();
+        for &addr in self.ipconfig.keys() {
+            if let Some((ip, base)) = self.ip_at_address(db, addr) {
+                if self.try_get_ip_type(&ip).is_some() {
+                    ips.insert(ip, base);
+                }
+            }
+        }
+        let present = |addr: u32| self.ipconfig.contains_key(&addr);
+        let mut decoded = DecodedIP::default();
+        // Configuration space that packing the decoded features would give
+        let mut rebuilt = BTreeMap::<u32, u8>::new();
+        for (ip, base) in ips {
+            let iptype = self.get_ip_type(&ip);
+            let tdb = &db.ip_bitdb(&self.family, iptype).db;
+            let get = |frame: usize, bit: usize| self.get_ip_bit(base, frame as u32, bit as u32);
+            let mut config = TileConfig::default();
+            if ip.starts_with("LRAM_") {
+                // See configure_ip: the whole LRAM is written, INITVAL_nn words
+                // being at a stride of 0x280 bytes
+                let w = match tdb.words.get("INITVAL_00") {
+                    Some(w) => w,
+                    None => continue,
+                };
+                if !(0..81920).all(|i| present(base + i)) {
+                    continue;
+                }
+                for n in 0..(81920 / 0x280) {
+                    let value: Vec<bool> = w
+                        .bits
+                        .iter()
+                        .map(|b| b.iter().all(|cb| self.get_ip_bit(base + 0x280 * n, cb.frame as u32, cb.bit as u32) != cb.invert))
+                        .collect();
+                    if value.iter().any(|&b| b) {
+                        config.words.insert(format!("INITVAL_{:02X}", n), value);
+                    }
+                }
+                // At least one word is needed for the LRAM to be written at all
+                if config.words.is_empty() {
+                    config.words.insert("INITVAL_00".to_string(), vec![false; w.bits.len()]);
+                }
+            } else {
+                for (name, edata) in tdb.enums.iter() {
+                    let best_match = edata
+                        .options
+                        .iter()
+                        .filter(|(_k, v)| {
+                            v.iter().any(|cb| !cb.invert)
+                                && v.iter().all(|cb| present(base + cb.frame as u32) && get(cb.frame, cb.bit) != cb.invert)
+                        })
+                        .max_by_key(|(_k, v)| v.len());
+                    if let Some((opt, _bits)) = best_match {
+                        config.enums.insert(name.to_string(), opt.to_string());
+                    }
+                }
+                for (name, wdata) in tdb.words.iter() {
+                    // Words are written in full, so only give those that were
+                    if !wdata.bits.iter().flatten().all(|cb| present(base + cb.frame as u32)) {
+                        continue;
+                    }
+                    let value = wdata
+                        .bits
+                        .iter()
+                        .map(|b| b.iter().all(|cb| get(cb.frame, cb.bit) != cb.invert))
+                        .collect();
+                    config.words.insert(name.to_string(), value);
+                }
+            }
+            if config.is_empty() {
+                continue;
+            }
+            // Check that packing the features doesn't write anything that
+            // wasn't there before, which raw bytes couldn't undo
+            let mut packed = BTreeMap::new();
+            let mut errors = Vec::new();
+            configure_ip_bits(&mut packed, &ip, base, tdb, &config.to_fasm_tile(), &mut errors);
+            if !errors.is_empty() || packed.keys().any(|&addr| !present(addr)) {
+                continue;
+            }
+            rebuilt.append(&mut packed);
+            decoded.ips.insert(ip, config);
+        }
+        // Raw bytes are applied after all named IP (see apply_fasm_features), so they
+        // correct any differences, including bits that aren't part of a known feature
+        decoded.unknown = self
+            .ipconfig
+            .iter()
+            .filter(|(addr, val)| rebuilt.get(addr) != Some(val))
+            .map(|(&addr, &val)| (addr, val))
+            .collect();
+        decoded
     }
     // Sets an IP bit
     pub fn set_ip_bit(&mut self, offset: u32, word: u32, bit: u32, value: bool) {
-        let byte = self.ipconfig.entry(offset + word).or_insert(0);
-        if value {
-            *byte |= 1 << bit;
-        } else {
-            *byte &= !(1 << bit);
-        }
+        set_ip_config_bit(&mut self.ipconfig, offset, word, bit, value);
     }
     // Set up tile groups
     pub fn create_tilegroups(&mut self, db: &mut Database) {
@@ -399,8 +1051,63 @@ impl Chip {
     // Apply a tilegroup to all tiles within it
     // This sets applicable words and enums to all tiles that match inside the tilegroup
     pub fn apply_tilegroup(&mut self, group: &str, db: &mut Database, ft: &FasmTile) {
-        let tg = self.tilegroups.get(group).unwrap_or_else(|| panic!("No tilegroup named {}", group)).clone();
+        self.try_apply_tilegroup(group, db, ft).unwrap_or_else(|errors| panic_on_fasm_errors(&errors))
+    }
+    pub fn try_apply_tilegroup(&mut self, group: &str, db: &mut Database, ft: &FasmTile) -> Result<(), Vec<FasmConfigError>> {
+        let tg = match self.tilegroups.get(group) {
+            Some(tg) => tg.clone(),
+            None => return Err(vec![FasmConfigError::new(ft.span.line, group, FasmConfigErrorKind::UnknownTile)
+                .suggest(self.tilegroups.keys().map(|k| k.as_str()))]),
+        };
         let tdbs : Vec<TileBitsDatabase> = tg.iter().map(|x| db.tile_bitdb(&self.family, &self.tile_by_name(x).unwrap().tiletype).db.clone()).collect();
+        let mut errors = Vec::new();
+        // Pips and unknown bits are specific to a tile, so can't be given for a group
+        let not_for_group = |line: usize, feature: String| {
+            FasmConfigError::new(line, group, FasmConfigErrorKind::NotForTileGroup(feature))
+        };
+        for (tw, fw) in ft.pips.iter().chain(ft.cleared_pips.iter().map(|(tw, fw)| (tw, fw))) {
+            errors.push(not_for_group(ft.pip_line(tw), format!("PIP.{}.{}", tw, fw)));
+        }
+        for (f, b) in ft.unknowns.iter().chain(ft.cleared_unknowns.iter()) {
+            errors.push(not_for_group(ft.span.line, format!("UNKNOWN.{}.{}", f, b)));
+        }
+        if let Some(val) = ft.always_on {
+            for (tile, tdb) in tg.iter().zip(tdbs.iter()) {
+                let tiledata = self.tile_by_name_mut(tile).unwrap();
+                for aon in tdb.always_on.iter() {
+                    tiledata.cram.set(aon.frame, aon.bit, val);
+                }
+            }
+        }
+        // Process explicit clears before anything is set
+        for (k, v) in ft.cleared_enums.iter() {
+            let line = ft.enum_line(k);
+            let mut found = false;
+            for (tile, tdb) in tg.iter().zip(tdbs.iter()) {
+                if let Some(en) = tdb.enums.get(k) {
+                    found = true;
+                    let opt = match en.options.get(v) {
+                        Some(opt) => opt,
+                        None => {
+                            errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::UnknownOption {
+                                name: k.to_string(),
+                                option: v.to_string(),
+                                valid: en.options.keys().cloned().collect(),
+                            }).suggest(en.options.keys().map(|o| o.as_str())));
+                            break;
+                        }
+                    };
+                    let tiledata = self.tile_by_name_mut(tile).unwrap();
+                    for bit in opt.iter() {
+                        tiledata.cram.set(bit.frame, bit.bit, bit.invert);
+                    }
+                }
+            }
+            if !found {
+                errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                    .suggest(tdbs.iter().flat_map(|tdb| tdb.enums.keys().map(|e| e.as_str()))));
+            }
+        }
         for i in 0..2 {
             // Process "BASE_" enums first
             for (k, v) in ft
@@ -408,156 +1115,242 @@ impl Chip {
                 .iter()
                 .filter(|(k, _)| k.starts_with("BASE_") == (i == 0) && !k.starts_with("UNKNOWN."))
             {
+                let line = ft.enum_line(k);
                 let mut found = false;
                 for (tile, tdb) in tg.iter().zip(tdbs.iter()) {
                     match tdb.enums.get(k) {
                         Some(en) => {
-                            let opt = en.options.get(v).unwrap_or_else(|| panic!("No option named {} for enum {} in tile {}.\n\
-Valid options are: {}\n\
-Please make sure Oxide and nextpnr are up to date and input source code is meaningful. If they are, consider reporting this as an issue.",
-                                        v, k, &tile,
-                                        en.options.keys().cloned().collect::<Vec<String>>().join(", ")));
+                            found = true;
+                            let opt = match en.options.get(v) {
+                                Some(opt) => opt,
+                                None => {
+                                    errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::UnknownOption {
+                                        name: k.to_string(),
+                                        option: v.to_string(),
+                                        valid: en.options.keys().cloned().collect(),
+                                    }).suggest(en.options.keys().map(|o| o.as_str())));
+                                    break;
+                                }
+                            };
                             let tiledata = self.tile_by_name_mut(&tile).unwrap();
                             for bit in opt.iter() {
                                 tiledata.cram.set(bit.frame, bit.bit, !bit.invert);
                             }
-                            found = true;
                         }
                         None => {}
                     }
                 }
                 if !found {
-                    panic!("No enum named {} in tilegroup {}.\n\
-Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, group);
+                    errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                        .suggest(tdbs.iter().flat_map(|tdb| tdb.enums.keys().map(|e| e.as_str()))));
                 }
             }
         }
         // Process words
         for (k, v) in ft.words.iter() {
+            let line = ft.word_line(k);
             let mut found = false;
             for (tile, tdb) in tg.iter().zip(tdbs.iter()) {
                 match tdb.words.get(k) {
                     Some(w) => {
+                        found = true;
                         if (v.significant_bits() as usize) > w.bits.len() {
-                            panic!(
-                                "Word {} in tile {} has value width {} exceeding database width of {}",
-                                k,
-                                &tile,
-                                v.significant_bits(),
-                                w.bits.len()
-                            );
+                            errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::WordTooWide {
+                                name: k.to_string(),
+                                width: v.significant_bits() as usize,
+                                max: w.bits.len(),
+                            }));
+                            break;
                         }
                         let tiledata = self.tile_by_name_mut(&tile).unwrap();
+                        let mask = ft.word_masks.get(k);
                         for (i, wb) in w.bits.iter().enumerate() {
+                            if mask.is_some_and(|m| !m.get_bit(i as u32)) {
+                                continue;
+                            }
                             let bit_val = v.get_bit(i as u32);
                             for bit in wb {
                                 tiledata.cram.set(bit.frame, bit.bit, bit.invert != bit_val);
                             }
                         }
-                        found = true;
                     }
                     None => {}
                 }
             }
             if !found {
-                panic!("No word named {} in tilegroup {}.\n\
-Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, group);
+                errors.push(FasmConfigError::new(line, group, FasmConfigErrorKind::UnknownWord(k.to_string()))
+                    .suggest(tdbs.iter().flat_map(|tdb| tdb.words.keys().map(|w| w.as_str()))));
             }
         }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
     }
     // Go from IP name to IP type
     pub fn get_ip_type(&self, ip: &str) -> &'static str {
+        self.try_get_ip_type(ip).unwrap_or_else(|| panic!("no IP data for {}", ip))
+    }
+    pub fn try_get_ip_type(&self, ip: &str) -> Option<&'static str> {
         if ip.starts_with("EBR_WID") {
-            return "EBR_INIT";
+            Some("EBR_INIT")
         } else if ip.starts_with("PLL_") {
-            return "PLL_CORE";
+            Some("PLL_CORE")
         } else if ip.starts_with("TDPHY_") {
-            return "DPHY_CORE";
+            Some("DPHY_CORE")
         } else if ip.starts_with("TPCIE_") {
-            return "PCIE_CORE";
+            Some("PCIE_CORE")
         } else if ip.starts_with("LRAM_") {
-            return "LRAM_INIT";
+            Some("LRAM_INIT")
         } else {
-            panic!("no IP data for {}", ip);
+            None
+        }
+    }
+    // Clear the bits of every option of each IP enum that FASM is about to set
+    fn clear_ip_enums(&mut self, ip: &str, db: &mut Database, ft: &FasmTile) {
+        let (baseaddr, iptype) = match (self.try_get_ip_baseaddr(db, ip), self.try_get_ip_type(ip)) {
+            (Some(b), Some(t)) => (b, t),
+            _ => return,
+        };
+        let tdb = &db.ip_bitdb(&self.family, iptype).db;
+        for k in ft.enums.keys() {
+            for bit in tdb.enums.get(k).iter().flat_map(|en| en.options.values()).flatten() {
+                self.set_ip_bit(baseaddr, bit.frame as u32, bit.bit as u32, bit.invert);
+            }
         }
     }
     // Configure an IP
     pub fn configure_ip(&mut self, ip: &str, db: &mut Database, ft: &FasmTile) {
+        self.try_configure_ip(ip, db, ft).unwrap_or_else(|errors| panic_on_fasm_errors(&errors))
+    }
+    pub fn try_configure_ip(&mut self, ip: &str, db: &mut Database, ft: &FasmTile) -> Result<(), Vec<FasmConfigError>> {
+        let tile = format!("IP_{}", ip);
+        let mut errors = Vec::new();
         // This is a special tile for currently-unknown IP bits
         if ip == "UNKNOWN" {
             for (k, v) in ft.words.iter() {
-                assert!(&k[0..2] == "0x");
-                let addr = u32::from_str_radix(&k[2..], 16).unwrap();
+                let addr = match k.strip_prefix("0x").and_then(|a| u32::from_str_radix(a, 16).ok()) {
+                    Some(addr) => addr,
+                    None => {
+                        let line = ft.word_line(k);
+                        errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownWord(k.to_string())));
+                        continue;
+                    }
+                };
                 for i in 0..8 {
                     let bit_val = v.get_bit(i);
                     self.set_ip_bit(0x0,  addr, i, bit_val);
                 }
             }
         } else {
-            let baseaddr = self.get_ip_baseaddr(db, ip);
-            let tdb = &db.ip_bitdb(&self.family, self.get_ip_type(ip)).db;
-            // Special PLL enable/update bit
-            if ip.starts_with("PLL_") {
-                self.set_ip_bit(baseaddr, 0, 0, true);
-            } else if ip.starts_with("LRAM_") {
-                // In order to avoid a multi-megabyte file and slow DB loads, we define most of the LRAM initialisation programmatically
-                assert!(ft.enums.is_empty());
-                // Full set of zeros is needed
-                for i in 0..81920 {
-                    self.set_ip_bit(baseaddr, i, 0, false);
-                }
-
-                for (k, v) in ft.words.iter() {
-                    assert!(k.starts_with("INITVAL_"));
-                    let init_word: u32 = u32::from_str_radix(&k[8..], 16).unwrap();
-                    let offset = 0x280 * init_word;
-                    let  w = tdb.words.get("INITVAL_00").unwrap();
-                    for (i, wb) in w.bits.iter().enumerate() {
-                        let bit_val = v.get_bit(i as u32);
-                        for bit in wb {
-                            self.set_ip_bit(baseaddr + offset, bit.frame as u32, bit.bit as u32, bit.invert != bit_val);
+            let (baseaddr, iptype) = match (self.try_get_ip_baseaddr(db, ip), self.try_get_ip_type(ip)) {
+                (Some(b), Some(t)) => (b, t),
+                _ => {
+                    let regions: Vec<String> = db
+                        .device_baseaddrs(&self.family, &self.device)
+                        .regions
+                        .keys()
+                        .map(|r| format!("IP_{}", r))
+                        .collect();
+                    return Err(vec![FasmConfigError::new(ft.span.line, &tile, FasmConfigErrorKind::UnknownIP)
+                        .suggest(regions.iter().map(|r| r.as_str()))]);
+                }
+            };
+            let tdb = &db.ip_bitdb(&self.family, iptype).db;
+            configure_ip_bits(&mut self.ipconfig, ip, baseaddr, tdb, ft, &mut errors);
+        }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
+    }
+    // Get the bits that are masked from frame ECC calculation, as (frame, bit)
+    // LUT RAM initialisation is masked as it changes at runtime; this covers
+    // the LUT INIT words of every slice configured as DPRAM. These are also the
+    // bits to ignore when verifying readback: EBR and LRAM contents are written over
+    // the config bus rather than stored in CRAM, so don't appear in readback at all
+    pub fn ecc_masked_bits(&self, db: &mut Database) -> BTreeSet<(usize, usize)> {
+        let mut masked = BTreeSet::new();
+        for t in self.tiles.iter() {
+            let tdb = &db.tile_bitdb(&self.family, &t.tiletype).db;
+            for (name, edata) in tdb.enums.iter() {
+                let slice = match name.strip_suffix(".MODE") {
+                    Some(s) if s.starts_with("SLICE") => s,
+                    _ => continue,
+                };
+                let is_ram = edata.options.get("DPRAM").is_some_and(|bits| {
+                    bits.iter().any(|cb| !cb.invert)
+                        && bits.iter().all(|cb| {
+                            self.cram.get(t.start_frame + cb.frame, t.start_bit + cb.bit) != cb.invert
+                        })
+                });
+                if !is_ram {
+                    continue;
+                }
+                for lut in &["K0", "K1"] {
+                    if let Some(w) = tdb.words.get(&format!("{}.{}.INIT", slice, lut)) {
+                        for cb in w.bits.iter().flatten() {
+                            masked.insert((t.start_frame + cb.frame, t.start_bit + cb.bit));
                         }
                     }
                 }
-                return;
             }
-            // Enums
-            for (k, v) in ft
-                .enums
-                .iter()
-            {
-                let en = tdb.enums.get(k).unwrap_or_else(|| panic!("No enum named {} in IP {}.\n\
-    Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, ip));
-                let opt = en.options.get(v).unwrap_or_else(|| panic!("No option named {} for enum {} in tile {}.\n\
-    Valid options are: {}\n\
-    Please make sure Oxide and nextpnr are up to date and input source code is meaningful. If they are, consider reporting this as an issue.",
-                            v, k, ip,
-                            en.options.keys().cloned().collect::<Vec<String>>().join(", ")));
-                for bit in opt.iter() {
-                    self.set_ip_bit(baseaddr, bit.frame as u32, bit.bit as u32, !bit.invert);
+        }
+        masked
+    }
+    // Find the tiles containing a chip-level CRAM bit, and the features in
+    // those tiles that use it
+    pub fn explain_bit(&self, db: &mut Database, frame: usize, bit: usize) -> Vec<TileBit> {
+        self.tiles
+            .iter()
+            .filter(|t| {
+                frame >= t.start_frame
+                    && frame < t.start_frame + t.cram.frames
+                    && bit >= t.start_bit
+                    && bit < t.start_bit + t.cram.bits
+            })
+            .map(|t| {
+                let (tf, tb) = (frame - t.start_frame, bit - t.start_bit);
+                TileBit {
+                    tile: t.name.to_string(),
+                    tiletype: t.tiletype.to_string(),
+                    frame: tf,
+                    bit: tb,
+                    features: db.tile_bitdb(&self.family, &t.tiletype).db.bit_features(tf, tb),
                 }
-            }
-            // Words
-            for (k, v) in ft.words.iter() {
-                let w = tdb.words.get(k).unwrap_or_else(|| panic!("No word named {} in IP {}.\n\
-    Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, ip));
-                if (v.significant_bits() as usize) > w.bits.len() {
-                    panic!(
-                        "Word {} in IP {} has value width {} exceeding database width of {}",
-                        k,
-                        ip,
-                        v.significant_bits(),
-                        w.bits.len()
-                    );
-                }
-                for (i, wb) in w.bits.iter().enumerate() {
-                    let bit_val = v.get_bit(i as u32);
-                    for bit in wb {
-                        self.set_ip_bit(baseaddr, bit.frame as u32, bit.bit as u32, bit.invert != bit_val);
-                    }
+            })
+            .collect()
+    }
+    // Compare the CRAM of a read back chip against this chip, which is what is expected
+    // Bits that change at runtime (see ecc_masked_bits) are ignored
+    pub fn verify_readback(&self, db: &mut Database, readback: &Chip) -> Result<Vec<BitMismatch>, DeviceMismatch> {
+        DeviceMismatch::check(self, readback)?;
+        let masked = self.ecc_masked_bits(db);
+        Ok(readback
+            .cram
+            .delta(&self.cram)
+            .into_iter()
+            .filter(|(f, b, _v)| !masked.contains(&(*f, *b)))
+            .map(|(f, b, v)| BitMismatch {
+                frame: f,
+                bit: b,
+                expected: !v,
+                tiles: self.explain_bit(db, f, b),
+            })
+            .collect())
+    }
+    // Compute the CRC32 over the configuration memory used for soft error detection
+    // Frames are covered in index order, each from the highest bit down,
+    // matching the order bits are shifted into the frame ECC. This has not yet
+    // been checked against a Radiant bitstream using the SED primitive, so it
+    // is only used when the parser is asked to check the SED CRC, and isn't
+    // written into bitstreams
+    pub fn compute_sed_crc(&self) -> u32 {
+        let mut crc: u32 = SED_CRC_INIT;
+        for frame in 0..self.data.frames {
+            for bit in (0..self.data.bits_per_frame).rev() {
+                let flag = (crc >> 31) != 0;
+                crc <<= 1;
+                if flag != self.cram.get(frame, bit) {
+                    crc ^= SED_CRC_POLY;
                 }
             }
         }
+        crc
     }
     // Lookup idcode
     pub fn get_idcode(&self) -> u32 {
@@ -598,8 +1391,74 @@ impl Tile {
             self.cram.print(&mut out);
         }
     }
+    // Clear the bits of every option of each enum, and every pip into each sink
+    // wire, that FASM is about to set, so the new setting replaces the old one
+    pub fn clear_replaced(&mut self, db: &mut Database, ft: &FasmTile) {
+        let tdb = db.tile_bitdb(&self.family, &self.tiletype);
+        for k in ft.enums.keys() {
+            for bit in tdb.db.enums.get(k).iter().flat_map(|en| en.options.values()).flatten() {
+                self.cram.set(bit.frame, bit.bit, bit.invert);
+            }
+        }
+        for tw in ft.pips.keys() {
+            for bit in tdb.db.pips.get(tw).iter().flat_map(|pips| pips.iter()).flat_map(|p| p.bits.iter()) {
+                self.cram.set(bit.frame, bit.bit, bit.invert);
+            }
+        }
+    }
     pub fn from_fasm(&mut self, db: &mut Database, ft: &FasmTile) {
+        self.try_from_fasm(db, ft).unwrap_or_else(|errors| panic_on_fasm_errors(&errors))
+    }
+    pub fn try_from_fasm(&mut self, db: &mut Database, ft: &FasmTile) -> Result<(), Vec<FasmConfigError>> {
         let tdb = db.tile_bitdb(&self.family, &self.tiletype);
+        let mut errors = Vec::new();
+        if let Some(val) = ft.always_on {
+            for aon in tdb.db.always_on.iter() {
+                self.cram.set(aon.frame, aon.bit, val);
+            }
+        }
+        // Process explicit clears before anything is set
+        for (k, v) in ft.cleared_enums.iter() {
+            let line = ft.enum_line(k);
+            match tdb.db.enums.get(k).map(|en| (en, en.options.get(v))) {
+                Some((_en, Some(opt))) => {
+                    for bit in opt.iter() {
+                        self.cram.set(bit.frame, bit.bit, bit.invert);
+                    }
+                }
+                Some((en, None)) => errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownOption {
+                    name: k.to_string(),
+                    option: v.to_string(),
+                    valid: en.options.keys().cloned().collect(),
+                }).suggest(en.options.keys().map(|o| o.as_str()))),
+                None => errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                    .suggest(tdb.db.enums.keys().map(|e| e.as_str()))),
+            }
+        }
+        for (tw, fw) in ft.cleared_pips.iter() {
+            let found_pip = tdb.db.pips.get(tw).and_then(|pips| pips.iter().find(|p| &p.from_wire == fw));
+            let found_fc = tdb.db.conns.get(tw).and_then(|conns| conns.iter().find(|c| &c.from_wire == fw));
+            match (found_pip, found_fc) {
+                (Some(p), _) => {
+                    for bit in p.bits.iter() {
+                        self.cram.set(bit.frame, bit.bit, bit.invert);
+                    }
+                }
+                // Fixed connections can't be cleared, but are never set either
+                (None, Some(_)) => {}
+                (None, None) => errors.push(FasmConfigError::new(ft.pip_line(tw), &self.name, FasmConfigErrorKind::UnknownPip {
+                    to_wire: tw.to_string(),
+                    from_wire: fw.to_string(),
+                })),
+            }
+        }
+        for &(f, b) in ft.cleared_unknowns.iter() {
+            if f >= self.cram.frames || b >= self.cram.bits {
+                errors.push(FasmConfigError::new(ft.span.line, &self.name, FasmConfigErrorKind::InvalidBit { frame: f, bit: b }));
+                continue;
+            }
+            self.cram.set(f, b, false);
+        }
         for i in 0..2 {
             // Process "BASE_" enums first
             for (k, v) in ft
@@ -607,13 +1466,26 @@ impl Tile {
                 .iter()
                 .filter(|(k, _)| k.starts_with("BASE_") == (i == 0) && !k.starts_with("UNKNOWN."))
             {
-                let en = tdb.db.enums.get(k).unwrap_or_else(|| panic!("No enum named {} in tile {}.\n\
-Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, self.name));
-                let opt = en.options.get(v).unwrap_or_else(|| panic!("No option named {} for enum {} in tile {}.\n\
-Valid options are: {}\n\
-Please make sure Oxide and nextpnr are up to date and input source code is meaningful. If they are, consider reporting this as an issue.",
-                            v, k, self.name,
-                            en.options.keys().cloned().collect::<Vec<String>>().join(", ")));
+                let line = ft.enum_line(k);
+                let en = match tdb.db.enums.get(k) {
+                    Some(en) => en,
+                    None => {
+                        errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                            .suggest(tdb.db.enums.keys().map(|e| e.as_str())));
+                        continue;
+                    }
+                };
+                let opt = match en.options.get(v) {
+                    Some(opt) => opt,
+                    None => {
+                        errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownOption {
+                            name: k.to_string(),
+                            option: v.to_string(),
+                            valid: en.options.keys().cloned().collect(),
+                        }).suggest(en.options.keys().map(|o| o.as_str())));
+                        continue;
+                    }
+                };
                 for bit in opt.iter() {
                     self.cram.set(bit.frame, bit.bit, !bit.invert);
                 }
@@ -621,18 +1493,29 @@ Please make sure Oxide and nextpnr are up to date and input source code is meani
         }
         // Process words
         for (k, v) in ft.words.iter() {
-            let w = tdb.db.words.get(k).unwrap_or_else(|| panic!("No word named {} in tile {}.\n\
-Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", k, self.name));
+            let line = ft.word_line(k);
+            let w = match tdb.db.words.get(k) {
+                Some(w) => w,
+                None => {
+                    errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownWord(k.to_string()))
+                        .suggest(tdb.db.words.keys().map(|w| w.as_str())));
+                    continue;
+                }
+            };
             if (v.significant_bits() as usize) > w.bits.len() {
-                panic!(
-                    "Word {} in tile {} has value width {} exceeding database width of {}",
-                    k,
-                    self.name,
-                    v.significant_bits(),
-                    w.bits.len()
-                );
+                errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::WordTooWide {
+                    name: k.to_string(),
+                    width: v.significant_bits() as usize,
+                    max: w.bits.len(),
+                }));
+                continue;
             }
+            let mask = ft.word_masks.get(k);
             for (i, wb) in w.bits.iter().enumerate() {
+                // Leave bits given as 'x' unchanged
+                if mask.is_some_and(|m| !m.get_bit(i as u32)) {
+                    continue;
+                }
                 let bit_val = v.get_bit(i as u32);
                 for bit in wb {
                     self.cram.set(bit.frame, bit.bit, bit.invert != bit_val);
@@ -645,7 +1528,7 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
                 .db
                 .pips
                 .get(tw)
-                .map_or(None, |pips| pips.iter().find(|p| &p.from_wire == fw));
+                .and_then(|pips| pips.iter().find(|p| &p.from_wire == fw));
             match found_pip {
                 Some(p) => {
                     for bit in p.bits.iter() {
@@ -653,29 +1536,46 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
                     }
                 }
                 None => {
-                    // Panic iff fixed connection doesn't exist
+                    // Error iff fixed connection doesn't exist
                     let found_fc = tdb
                         .db
                         .conns
                         .get(tw)
-                        .map_or(None, |conns| conns.iter().find(|c| &c.from_wire == fw));
+                        .and_then(|conns| conns.iter().find(|c| &c.from_wire == fw));
                     if found_fc.is_none() {
-                        panic!("No pip {}.{} in tile {}.\n\
-Please make sure Oxide and nextpnr are up to date. If they are, consider reporting this as an issue.", fw, tw, self.name);
+                        let line = ft.pip_line(tw);
+                        let candidates: Vec<String> = tdb
+                            .db
+                            .pips
+                            .iter()
+                            .flat_map(|(to, pips)| pips.iter().map(move |p| format!("PIP.{}.{}", to, p.from_wire)))
+                            .chain(tdb.db.conns.iter().flat_map(|(to, conns)| {
+                                conns.iter().map(move |c| format!("PIP.{}.{}", to, c.from_wire))
+                            }))
+                            .collect();
+                        errors.push(FasmConfigError::new(line, &self.name, FasmConfigErrorKind::UnknownPip {
+                            to_wire: tw.to_string(),
+                            from_wire: fw.to_string(),
+                        }).suggest(candidates.iter().map(|c| c.as_str())));
                     }
                 }
             }
         }
         // Process unknowns
-        for (f, b) in ft.unknowns.iter() {
-            self.cram.set(*f, *b, true);
+        for &(f, b) in ft.unknowns.iter() {
+            if f >= self.cram.frames || b >= self.cram.bits {
+                errors.push(FasmConfigError::new(ft.span.line, &self.name, FasmConfigErrorKind::InvalidBit { frame: f, bit: b }));
+                continue;
+            }
+            self.cram.set(f, b, true);
         }
+        if errors.is_empty() { Ok(()) } else { Err(errors) }
     }
-    pub fn write_fasm(&self, db: &mut Database, mut out: &mut dyn Write) {
+    // Decode the tile's configuration into database features
+    pub fn decode(&self, db: &mut Database) -> TileConfig {
         let tdb = db.tile_bitdb(&self.family, &self.tiletype);
-        let fasm_name = self.name.replace(':', "__");
+        let mut config = TileConfig::default();
         let mut known_bits = BTreeSet::<(usize, usize)>::new();
-        let mut total_matches = 0;
         for (to_wire, pips) in tdb.db.pips.iter() {
             let best_match = pips
                 .iter()
@@ -683,21 +1583,13 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
                     p.bits.iter().any(|cb| !cb.invert)
                         && p.bits
                             .iter()
-                            .all(|cb| self.cram.get(cb.frame, cb.bit) == !cb.invert)
+                            .all(|cb| self.cram.get(cb.frame, cb.bit) != cb.invert)
                 })
                 .max_by_key(|p| p.bits.len());
             if let Some(m) = best_match {
-                writeln!(
-                    &mut out,
-                    "{}.PIP.{}.{}",
-                    fasm_name,
-                    to_wire.replace(':', "__"),
-                    m.from_wire.replace(':', "__")
-                )
-                .unwrap();
+                config.pips.insert(to_wire.to_string(), m.from_wire.to_string());
                 let mut matched_bits = m.bits.iter().map(|cb| (cb.frame, cb.bit)).collect();
                 known_bits.append(&mut matched_bits);
-                total_matches += 1;
             }
         }
         for (name, edata) in tdb.db.enums.iter() {
@@ -707,14 +1599,13 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
                 .filter(|(_k, v)| {
                     v.iter().any(|cb| !cb.invert)
                         && v.iter()
-                            .all(|cb| self.cram.get(cb.frame, cb.bit) == !cb.invert)
+                            .all(|cb| self.cram.get(cb.frame, cb.bit) != cb.invert)
                 })
                 .max_by_key(|(_k, v)| v.len());
             if let Some((opt, bits)) = best_match {
-                writeln!(&mut out, "{}.{}.{}", fasm_name, name, opt).unwrap();
+                config.enums.insert(name.to_string(), opt.to_string());
                 let mut matched_bits = bits.iter().map(|cb| (cb.frame, cb.bit)).collect();
                 known_bits.append(&mut matched_bits);
-                total_matches += 1;
             }
         }
         for (name, wdata) in tdb.db.words.iter() {
@@ -727,30 +1618,12 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
             {
                 continue;
             }
-            let bitstr: String = wdata
+            let value = wdata
                 .bits
                 .iter()
-                .rev()
-                .map(|b| {
-                    match b
-                        .iter()
-                        .all(|cb| self.cram.get(cb.frame, cb.bit) == !cb.invert)
-                    {
-                        true => '1',
-                        false => '0',
-                    }
-                })
+                .map(|b| b.iter().all(|cb| self.cram.get(cb.frame, cb.bit) != cb.invert))
                 .collect();
-            writeln!(
-                &mut out,
-                "{}.{}[{}:0] = {}'b{}",
-                fasm_name,
-                name,
-                wdata.bits.len() - 1,
-                wdata.bits.len(),
-                bitstr
-            )
-            .unwrap();
+            config.words.insert(name.to_string(), value);
             let mut matched_bits = wdata
                 .bits
                 .iter()
@@ -758,25 +1631,475 @@ Please make sure Oxide and nextpnr are up to date. If they are, consider reporti
                 .map(|cb| (cb.frame, cb.bit))
                 .collect();
             known_bits.append(&mut matched_bits);
-            total_matches += 1;
         }
         for aon in tdb.db.always_on.iter() {
             if self.cram.get(aon.frame, aon.bit) {
                 known_bits.insert((aon.frame, aon.bit));
             } else {
-                warn!("Supposedly always on bit F{}B{} in {} found to be cleared!\n", aon.frame, aon.bit, fasm_name);
+                warn!("Supposedly always on bit F{}B{} in {} found to be cleared!\n", aon.frame, aon.bit,
+                    self.name.replace(':', "__"));
+            }
+        }
+        config.unknowns = self.cram.set_bits().difference(&known_bits).cloned().collect();
+        config
+    }
+    pub fn write_fasm(&self, db: &mut Database, out: &mut dyn Write) {
+        self.write_fasm_impl(db, out, false);
+    }
+    // As write_fasm, but with comments giving the tile location, the bel and
+    // database description of each feature, and a summary of unknown bits
+    pub fn write_annotated_fasm(&self, db: &mut Database, out: &mut dyn Write) {
+        self.write_fasm_impl(db, out, true);
+    }
+    // Bels (named as in tilegroups) that a feature prefix such as "SLICEA" or
+    // "PIOB" belongs to
+    fn feature_bels(&self, bels: &[Bel], feature: &str) -> Vec<String> {
+        let prefix = feature.split('.').next().unwrap_or("");
+        bels.iter()
+            .filter(|b| b.name == prefix || b.name.starts_with(&format!("{}_", prefix)))
+            .map(|b| self.bel_name(b))
+            .collect()
+    }
+    // Bel pins connected to a tile wire
+    fn wire_bel_pins(&self, bels: &[Bel], wire: &str) -> Vec<String> {
+        bels.iter()
+            .flat_map(|b| {
+                b.pins
+                    .iter()
+                    .filter(move |p| p.wire.rel_name(b.rel_x, b.rel_y) == wire)
+                    .map(move |p| format!("{}.{}", self.bel_name(b), p.name))
+            })
+            .collect()
+    }
+    fn bel_name(&self, bel: &Bel) -> String {
+        format!(
+            "R{}C{}_{}",
+            (self.y as i32) + bel.rel_y,
+            (self.x as i32) + bel.rel_x,
+            bel.name
+        )
+    }
+    fn write_fasm_impl(&self, db: &mut Database, mut out: &mut dyn Write, annotate: bool) {
+        let config = self.decode(db);
+        if config.is_empty() {
+            return;
+        }
+        let tdb = db.tile_bitdb(&self.family, &self.tiletype);
+        let bels = if annotate {
+            get_tile_bels(&self.tiletype, &tdb.db)
+        } else {
+            Vec::new()
+        };
+        // Comment to follow a feature, if annotating and there is anything to say
+        let comment = |parts: Vec<String>| {
+            let parts: Vec<String> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+            if !annotate || parts.is_empty() {
+                String::new()
+            } else {
+                format!(" # {}", parts.join("; "))
+            }
+        };
+        let bel_list = |bels: Vec<String>| match bels.len() {
+            0 => String::new(),
+            1 => format!("bel {}", bels[0]),
+            _ => format!("bels {}", bels.join(", ")),
+        };
+        let fasm_name = self.name.replace(':', "__");
+        if annotate {
+            writeln!(
+                &mut out,
+                "# {} ({}) at x={}, y={}",
+                fasm_name, self.tiletype, self.x, self.y
+            )
+            .unwrap();
+        }
+        for (to_wire, from_wire) in config.pips.iter() {
+            let mut pins = self.wire_bel_pins(&bels, from_wire);
+            pins.extend(self.wire_bel_pins(&bels, to_wire));
+            writeln!(
+                &mut out,
+                "{}.PIP.{}.{}{}",
+                fasm_name,
+                to_wire.replace(':', "__"),
+                from_wire.replace(':', "__"),
+                comment(vec![pins.join(", ")])
+            )
+            .unwrap();
+        }
+        for (name, opt) in config.enums.iter() {
+            let desc = tdb.db.enums.get(name).map(|e| e.desc.to_string()).unwrap_or_default();
+            writeln!(
+                &mut out,
+                "{}.{}.{}{}",
+                fasm_name,
+                name,
+                opt,
+                comment(vec![desc, bel_list(self.feature_bels(&bels, name))])
+            )
+            .unwrap();
+        }
+        for (name, value) in config.words.iter() {
+            let desc = tdb.db.words.get(name).map(|w| w.desc.to_string()).unwrap_or_default();
+            writeln!(
+                &mut out,
+                "{}.{}[{}:0] = {}{}",
+                fasm_name,
+                name,
+                value.len() - 1,
+                format_word(value),
+                comment(vec![desc, bel_list(self.feature_bels(&bels, name))])
+            )
+            .unwrap();
+        }
+        if annotate && !config.unknowns.is_empty() {
+            writeln!(
+                &mut out,
+                "# {} unknown bit{} in {}",
+                config.unknowns.len(),
+                if config.unknowns.len() == 1 { "" } else { "s" },
+                fasm_name
+            )
+            .unwrap();
+        }
+        for (f, b) in config.unknowns.iter() {
+            writeln!(&mut out, "{}.UNKNOWN.{}.{}", fasm_name, f, b).unwrap();
+        }
+        writeln!(&mut out).unwrap();
+    }
+}
+
+// Configuration of a tile, decoded into features
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TileConfig {
+    // Enabled pips, as sink wire to source wire
+    pub pips: BTreeMap<String, String>,
+    // Enum settings, as enum to option
+    pub enums: BTreeMap<String, String>,
+    // Word values with any bits set, LSB first
+    pub words: BTreeMap<String, Vec<bool>>,
+    // Set bits that aren't part of any feature, as (frame, bit)
+    pub unknowns: BTreeSet<(usize, usize)>,
+}
+
+impl TileConfig {
+    pub fn is_empty(&self) -> bool {
+        self.pips.is_empty() && self.enums.is_empty() && self.words.is_empty() && self.unknowns.is_empty()
+    }
+    // The FASM features that would configure this
+    pub fn to_fasm_tile(&self) -> FasmTile {
+        let mut ft = FasmTile::new();
+        for (to_wire, from_wire) in self.pips.iter() {
+            ft.pips.insert(to_wire.to_string(), from_wire.to_string());
+        }
+        for (name, opt) in self.enums.iter() {
+            ft.enums.insert(name.to_string(), opt.to_string());
+        }
+        for (name, value) in self.words.iter() {
+            let mut v = Integer::new();
+            for (i, &b) in value.iter().enumerate() {
+                v.set_bit(i as u32, b);
+            }
+            ft.words.insert(name.to_string(), v);
+        }
+        ft.unknowns = self.unknowns.iter().cloned().collect();
+        ft
+    }
+}
+
+// IP configuration space decoded into features
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecodedIP {
+    // Features of each IP, by IP name
+    pub ips: BTreeMap<String, TileConfig>,
+    // Bytes not fully described by the features, by address
+    pub unknown: BTreeMap<u32, u8>,
+}
+
+// Sets a bit in IP configuration space
+fn set_ip_config_bit(ipconfig: &mut BTreeMap<u32, u8>, offset: u32, word: u32, bit: u32, value: bool) {
+    let byte = ipconfig.entry(offset + word).or_insert(0);
+    if value {
+        *byte |= 1 << bit;
+    } else {
+        *byte &= !(1 << bit);
+    }
+}
+
+// Write the features of a named IP at `baseaddr` into IP configuration space,
+// collecting any that aren't in the IP's database
+fn configure_ip_bits(
+    ipconfig: &mut BTreeMap<u32, u8>,
+    ip: &str,
+    baseaddr: u32,
+    tdb: &TileBitsDatabase,
+    ft: &FasmTile,
+    errors: &mut Vec<FasmConfigError>,
+) {
+    let tile = format!("IP_{}", ip);
+    // Special PLL enable/update bit
+    if ip.starts_with("PLL_") {
+        set_ip_config_bit(ipconfig, baseaddr, 0, 0, true);
+    } else if ip.starts_with("LRAM_") {
+        // In order to avoid a multi-megabyte file and slow DB loads, we define most of the LRAM initialisation programmatically
+        for k in ft.enums.keys() {
+            let line = ft.enum_line(k);
+            errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownEnum(k.to_string())));
+        }
+        // Full set of zeros is needed
+        for i in 0..81920 {
+            set_ip_config_bit(ipconfig, baseaddr, i, 0, false);
+        }
+
+        for (k, v) in ft.words.iter() {
+            let init_word = match k.strip_prefix("INITVAL_").and_then(|w| u32::from_str_radix(w, 16).ok()) {
+                Some(w) => w,
+                None => {
+                    let line = ft.word_line(k);
+                    errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownWord(k.to_string())));
+                    continue;
+                }
+            };
+            let offset = 0x280 * init_word;
+            let  w = tdb.words.get("INITVAL_00").unwrap();
+            for (i, wb) in w.bits.iter().enumerate() {
+                let bit_val = v.get_bit(i as u32);
+                for bit in wb {
+                    set_ip_config_bit(ipconfig, baseaddr + offset, bit.frame as u32, bit.bit as u32, bit.invert != bit_val);
+                }
             }
         }
-        for f in 0..self.cram.frames {
-            for b in 0..self.cram.bits {
-                if self.cram.get(f, b) && !known_bits.contains(&(f, b)) {
-                    writeln!(&mut out, "{}.UNKNOWN.{}.{}", fasm_name, f, b).unwrap();
-                    total_matches += 1;
+        return;
+    }
+    // Explicitly cleared enum options
+    for (k, v) in ft.cleared_enums.iter() {
+        match tdb.enums.get(k).and_then(|en| en.options.get(v)) {
+            Some(opt) => {
+                for bit in opt.iter() {
+                    set_ip_config_bit(ipconfig, baseaddr, bit.frame as u32, bit.bit as u32, bit.invert);
                 }
             }
+            None => errors.push(FasmConfigError::new(ft.enum_line(k), &tile,
+                FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                .suggest(tdb.enums.keys().map(|e| e.as_str()))),
+        }
+    }
+    // Enums
+    for (k, v) in ft
+        .enums
+        .iter()
+    {
+        let line = ft.enum_line(k);
+        let en = match tdb.enums.get(k) {
+            Some(en) => en,
+            None => {
+                errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownEnum(k.to_string()))
+                    .suggest(tdb.enums.keys().map(|e| e.as_str())));
+                continue;
+            }
+        };
+        let opt = match en.options.get(v) {
+            Some(opt) => opt,
+            None => {
+                errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownOption {
+                    name: k.to_string(),
+                    option: v.to_string(),
+                    valid: en.options.keys().cloned().collect(),
+                }).suggest(en.options.keys().map(|o| o.as_str())));
+                continue;
+            }
+        };
+        for bit in opt.iter() {
+            set_ip_config_bit(ipconfig, baseaddr, bit.frame as u32, bit.bit as u32, !bit.invert);
         }
-        if total_matches > 0 {
-            writeln!(&mut out, "").unwrap();
+    }
+    // Words
+    for (k, v) in ft.words.iter() {
+        let line = ft.word_line(k);
+        let w = match tdb.words.get(k) {
+            Some(w) => w,
+            None => {
+                errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::UnknownWord(k.to_string()))
+                    .suggest(tdb.words.keys().map(|w| w.as_str())));
+                continue;
+            }
+        };
+        if (v.significant_bits() as usize) > w.bits.len() {
+            errors.push(FasmConfigError::new(line, &tile, FasmConfigErrorKind::WordTooWide {
+                name: k.to_string(),
+                width: v.significant_bits() as usize,
+                max: w.bits.len(),
+            }));
+            continue;
         }
+        let mask = ft.word_masks.get(k);
+        for (i, wb) in w.bits.iter().enumerate() {
+            if mask.is_some_and(|m| !m.get_bit(i as u32)) {
+                continue;
+            }
+            let bit_val = v.get_bit(i as u32);
+            for bit in wb {
+                set_ip_config_bit(ipconfig, baseaddr, bit.frame as u32, bit.bit as u32, bit.invert != bit_val);
+            }
+        }
+    }
+}
+
+// Format a word value (LSB first) as a FASM binary literal
+pub fn format_word(value: &[bool]) -> String {
+    let bitstr: String = value
+        .iter()
+        .rev()
+        .map(|b| match b {
+            true => '1',
+            false => '0',
+        })
+        .collect();
+    format!("{}'b{}", value.len(), bitstr)
+}
+
+// Format a word value (LSB first) as a FASM hex literal, for wide words
+pub fn format_word_hex(value: &[bool]) -> String {
+    let hexstr: String = value
+        .chunks(4)
+        .rev()
+        .map(|c| {
+            let nibble = c.iter().enumerate().fold(0, |acc, (i, &b)| acc | ((b as u32) << i));
+            std::char::from_digit(nibble, 16).unwrap().to_ascii_uppercase()
+        })
+        .collect();
+    format!("{}'h{}", value.len(), hexstr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_database;
+
+    // Rows of 150 bits span three words, the last partially used
+    fn pattern(frames: usize, bits: usize) -> BitMatrix {
+        let mut m = BitMatrix::new(frames, bits);
+        for f in 0..frames {
+            for b in 0..bits {
+                m.set(f, b, (f * 31 + b * 7) % 5 == 0);
+            }
+        }
+        m
+    }
+
+    #[test]
+    fn bitmatrix_read_write_bits() {
+        let mut m = BitMatrix::new(3, 150);
+        // Crossing the first and second word boundaries, and a full word at an offset
+        m.write_bits(1, 60, 10, 0x3FF);
+        m.write_bits(1, 120, 30, 0x2AAAAAAA);
+        assert_eq!(m.read_bits(1, 58, 14), 0xFFC);
+        assert_eq!(m.read_bits(1, 120, 30), 0x2AAAAAAA);
+        let ones: Vec<_> = m.set_bits().into_iter().collect();
+        let mut expected: Vec<_> = (60..70).map(|b| (1, b)).collect();
+        expected.extend((121..150).step_by(2).map(|b| (1, b)));
+        assert_eq!(ones, expected);
+        assert_eq!(m.count_ones(), 25);
+
+        // Only the written bits change; values are truncated to the length
+        m.write_bits(1, 32, 64, 0);
+        m.write_bits(1, 62, 4, !0);
+        assert_eq!(m.read_bits(1, 0, 64), 0xC000000000000000);
+        assert_eq!(m.read_bits(1, 64, 64), 0x3 | (0xAAAAAAAAu64 << 56));
+        assert_eq!(m.count_ones(), 4 + 15);
+        assert!(!m.row(0).iter().chain(m.row(2)).any(|w| *w != 0));
+    }
+
+    #[test]
+    fn bitmatrix_copy_and_delta() {
+        let from = pattern(4, 150);
+        // Offsets that don't align any of the source words to the destination
+        let mut m = BitMatrix::new(7, 230);
+        m.copy_window(&from, 2, 37);
+        for f in 0..7 {
+            for b in 0..230 {
+                let inside = (2..6).contains(&f) && (37..187).contains(&b);
+                assert_eq!(m.get(f, b), inside && from.get(f - 2, b - 37));
+            }
+        }
+        assert_eq!(m.count_ones(), from.count_ones());
+        let mut back = BitMatrix::new(4, 150);
+        back.copy_from_window(&m, 2, 37);
+        assert!(back == from);
+
+        let mut changed = from.clone();
+        changed.set(3, 149, !from.get(3, 149));
+        changed.set(0, 64, !from.get(0, 64));
+        assert_eq!(changed.delta(&from), vec![(0, 64, !from.get(0, 64)), (3, 149, !from.get(3, 149))]);
+        assert_eq!(from.delta(&from), vec![]);
+    }
+
+    #[test]
+    fn apply_fasm_replaces() {
+        let mut db = test_database();
+        let fasm = |s: &str| ParsedFasm::parse_str(s).unwrap();
+        let mut ch = Chip::try_from_fasm(&mut db, &fasm("R1C1__PLC.PIP.A0.B0
+R1C1__PLC.SLICEA.MODE.RAMW
+R1C1__PLC.K0.INIT[3:0] = 4'hF
+IP_PLL_LLC.CLKOP_EN.ENABLED
+IP_PLL_LLC.DIVOP[9:0] = 10'h2A5
+"), Some("TEST")).unwrap();
+        let word = |v: u32, width: usize| (0..width).map(|i| (v >> i) & 1 == 1).collect::<Vec<_>>();
+        let tile = |ch: &Chip, db: &mut Database| ch.tile_by_name("R1C1:PLC").unwrap().decode(db);
+
+        // Setting an option or word replaces the old one, and a pip can be cleared
+        ch.try_apply_fasm(&mut db, &fasm("R1C1__PLC.SLICEA.MODE.CCU2
+R1C1__PLC.K0.INIT[3:0] = 4'h5
+R1C1__PLC.PIP.A0.B0 = 0
+")).unwrap();
+        let t = tile(&ch, &mut db);
+        assert!(t.pips.is_empty());
+        assert_eq!(t.enums["SLICEA.MODE"], "CCU2");
+        assert_eq!(t.words["K0.INIT"], word(0x5, 4));
+
+        // A pip replaces the one driving the same wire, even where its bits are a subset
+        ch.try_apply_fasm(&mut db, &fasm("R1C1__PLC.PIP.A0.B0\n")).unwrap();
+        ch.try_apply_fasm(&mut db, &fasm("R1C1__PLC.PIP.A0.B1\n")).unwrap();
+        assert_eq!(tile(&ch, &mut db).pips["A0"], "B1");
+
+        // IP enums are replaced too, and raw IP bytes are applied last wherever
+        // they are in the file
+        ch.try_apply_fasm(&mut db, &fasm("IP_PLL_LLC.CLKOP_EN.DISABLED\n")).unwrap();
+        assert_eq!(ch.decode_ip(&mut db).ips["PLL_LLC"].enums["CLKOP_EN"], "DISABLED");
+        ch.try_apply_fasm(&mut db, &fasm("IP_UNKNOWN.0x0E000002[7:0] = 8'h00
+IP_PLL_LLC.CLKOP_EN.ENABLED
+IP_PLL_LLC.DIVOP[9:0] = 10'h001
+")).unwrap();
+        let ip = ch.decode_ip(&mut db).ips.remove("PLL_LLC").unwrap();
+        assert_eq!(ch.ipconfig[&0x0E000002], 0x00);
+        assert!(ip.enums.is_empty());
+        assert_eq!(ip.words["DIVOP"], word(0x001, 10));
+    }
+
+    #[test]
+    fn readback_masks_ram_init() {
+        let mut db = test_database();
+        let mut expected = Chip::from_name(&mut db, "TEST");
+        let t = expected.tile_by_name("R1C1:PLC").unwrap();
+        let (f, b) = (t.start_frame, t.start_bit);
+        // Bits are tile-relative to R1C1:PLC: SLICEB.K0.INIT is F7B0..1 and
+        // SLICEB.MODE DPRAM is F8B4; K0.INIT (F3B0..3) belongs to no slice
+        assert!(expected.ecc_masked_bits(&mut db).is_empty());
+        expected.cram.set(f + 8, b + 4, true);
+        assert_eq!(
+            expected.ecc_masked_bits(&mut db).into_iter().collect::<Vec<_>>(),
+            vec![(f + 7, b), (f + 7, b + 1)]
+        );
+
+        let mut readback = expected.clone();
+        readback.cram.set(f + 7, b, true);
+        assert_eq!(expected.verify_readback(&mut db, &readback).unwrap(), vec![]);
+
+        readback.cram.set(f + 3, b + 2, true);
+        let mismatches = expected.verify_readback(&mut db, &readback).unwrap();
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!((mismatches[0].frame, mismatches[0].bit, mismatches[0].expected), (f + 3, b + 2, false));
+        assert_eq!(mismatches[0].tiles.len(), 1);
+        let tb = &mismatches[0].tiles[0];
+        assert_eq!((tb.tile.as_str(), tb.frame, tb.bit), ("R1C1:PLC", 3, 2));
     }
 }
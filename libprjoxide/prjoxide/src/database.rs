@@ -241,6 +241,55 @@ impl fmt::Debug for ConfigBit {
     }
 }
 
+// A feature in a tile that uses a particular config bit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BitFeature {
+    Pip {
+        to_wire: String,
+        from_wire: String,
+        invert: bool,
+    },
+    Enum {
+        name: String,
+        option: String,
+        invert: bool,
+    },
+    Word {
+        name: String,
+        index: usize,
+        invert: bool,
+    },
+    AlwaysOn,
+}
+
+impl fmt::Display for BitFeature {
+    // Formatted like the FASM feature, relative to the tile
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let invert = match self {
+            BitFeature::Pip { to_wire, from_wire, invert } => {
+                write!(f, "PIP.{}.{}", to_wire, from_wire)?;
+                *invert
+            }
+            BitFeature::Enum { name, option, invert } => {
+                write!(f, "{}.{}", name, option)?;
+                *invert
+            }
+            BitFeature::Word { name, index, invert } => {
+                write!(f, "{}[{}]", name, index)?;
+                *invert
+            }
+            BitFeature::AlwaysOn => {
+                write!(f, "ALWAYS_ON")?;
+                false
+            }
+        };
+        if invert {
+            write!(f, " (inverted)")?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ConfigPipData {
     pub from_wire: String,
@@ -298,6 +347,49 @@ impl TileBitsDatabase {
         return sources;
     }
 
+    // Get the features that use a given bit
+    pub fn bit_features(&self, frame: usize, bit: usize) -> Vec<BitFeature> {
+        let find_bit = |bits: &BTreeSet<ConfigBit>| bits.iter().find(|cb| cb.frame == frame && cb.bit == bit).map(|cb| cb.invert);
+        let mut features = Vec::new();
+        for (to_wire, pips) in self.pips.iter() {
+            for pip in pips.iter() {
+                if let Some(invert) = find_bit(&pip.bits) {
+                    features.push(BitFeature::Pip {
+                        to_wire: to_wire.to_string(),
+                        from_wire: pip.from_wire.to_string(),
+                        invert,
+                    });
+                }
+            }
+        }
+        for (name, edata) in self.enums.iter() {
+            for (opt, bits) in edata.options.iter() {
+                if let Some(invert) = find_bit(bits) {
+                    features.push(BitFeature::Enum {
+                        name: name.to_string(),
+                        option: opt.to_string(),
+                        invert,
+                    });
+                }
+            }
+        }
+        for (name, wdata) in self.words.iter() {
+            for (index, bits) in wdata.bits.iter().enumerate() {
+                if let Some(invert) = find_bit(bits) {
+                    features.push(BitFeature::Word {
+                        name: name.to_string(),
+                        index,
+                        invert,
+                    });
+                }
+            }
+        }
+        if find_bit(&self.always_on).is_some() {
+            features.push(BitFeature::AlwaysOn);
+        }
+        features
+    }
+
     pub fn get_sink_wires(&self) -> BTreeSet<String> {
         let mut sinks = BTreeSet::new();
         for pip_sink in self.pips.keys() {
@@ -517,6 +609,14 @@ impl Database {
             }
         }
     }
+    // Names of all devices in the database
+    pub fn device_names(&self) -> Vec<String> {
+        self.devices
+            .families
+            .values()
+            .flat_map(|fd| fd.devices.keys().cloned())
+            .collect()
+    }
     // Both functions return a (family, name, data) 3-tuple
     pub fn device_by_name(&self, name: &str) -> Option<(String, String, DeviceData)> {
         for (f, fd) in self.devices.families.iter() {
@@ -697,3 +797,9 @@ impl Database {
         }
     }
 }
+
+// Database for a small made up device, LIFCL "TEST", for unit tests
+#[cfg(test)]
+pub(crate) fn test_database() -> Database {
+    Database::new(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/database"))
+}
@@ -0,0 +1,355 @@
+use crate::chip::*;
+use crate::database::Database;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// Semantic comparison of two configurations
+//
+// Rather than the raw bits of Chip::delta, changes are reported in terms of
+// the database features that unpack would emit, grouped by tile or IP.
+
+// A change to one feature of a tile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FeatureChange {
+    PipAdded { to_wire: String, from_wire: String },
+    PipRemoved { to_wire: String, from_wire: String },
+    // An enum option; None if no option matched
+    EnumChanged {
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    // A word value, LSB first; all zeros if the word was not set
+    WordChanged {
+        name: String,
+        old: Vec<bool>,
+        new: Vec<bool>,
+    },
+    // A set bit that isn't part of any feature appeared or disappeared
+    UnknownBit { frame: usize, bit: usize, value: bool },
+}
+
+impl fmt::Display for FeatureChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opt_name = |o: &Option<String>| o.clone().unwrap_or_else(|| "<none>".to_string());
+        match self {
+            FeatureChange::PipAdded { to_wire, from_wire } => {
+                write!(f, "+ PIP.{}.{}", to_wire, from_wire)
+            }
+            FeatureChange::PipRemoved { to_wire, from_wire } => {
+                write!(f, "- PIP.{}.{}", to_wire, from_wire)
+            }
+            FeatureChange::EnumChanged { name, old, new } => {
+                write!(f, "~ {}: {} -> {}", name, opt_name(old), opt_name(new))
+            }
+            FeatureChange::WordChanged { name, old, new } => write!(
+                f,
+                "~ {}: {} -> {}",
+                name,
+                format_word(old),
+                format_word(new)
+            ),
+            FeatureChange::UnknownBit { frame, bit, value } => write!(
+                f,
+                "{} UNKNOWN.{}.{}",
+                if *value { "+" } else { "-" },
+                frame,
+                bit
+            ),
+        }
+    }
+}
+
+// All changes to a tile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileDiff {
+    pub tile: String,
+    pub tiletype: String,
+    pub changes: Vec<FeatureChange>,
+}
+
+// A changed byte in IP configuration space
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IPChange {
+    // Offset from the base address of the IP
+    pub offset: u32,
+    pub old: u8,
+    pub new: u8,
+}
+
+// All changes to an IP; the IP is "UNKNOWN" for addresses outside any known
+// IP, with offsets then being absolute addresses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IPDiff {
+    pub ip: String,
+    // Changes to named IP features, where both configurations decode (see Chip::decode_ip)
+    pub features: Vec<FeatureChange>,
+    // Changed bytes that the features don't account for
+    pub changes: Vec<IPChange>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChipDiff {
+    pub tiles: Vec<TileDiff>,
+    pub ips: Vec<IPDiff>,
+}
+
+impl ChipDiff {
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty() && self.ips.is_empty()
+    }
+    // Print the diff in a human readable format
+    pub fn print(&self, out: &mut dyn std::io::Write) -> std::io::Result<()> {
+        for t in self.tiles.iter() {
+            writeln!(out, "{} ({})", t.tile, t.tiletype)?;
+            for c in t.changes.iter() {
+                writeln!(out, "    {}", c)?;
+            }
+        }
+        for ip in self.ips.iter() {
+            writeln!(out, "IP {}", ip.ip)?;
+            for c in ip.features.iter() {
+                writeln!(out, "    {}", c)?;
+            }
+            for c in ip.changes.iter() {
+                writeln!(out, "    ~ 0x{:08X}: 0x{:02X} -> 0x{:02X}", c.offset, c.old, c.new)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Compare the decoded features of one tile in two configurations
+pub fn diff_tile(db: &mut Database, old: &Tile, new: &Tile) -> Vec<FeatureChange> {
+    diff_config(&old.decode(db), &new.decode(db))
+}
+
+// Compare two sets of decoded features
+pub fn diff_config(c0: &TileConfig, c1: &TileConfig) -> Vec<FeatureChange> {
+    let mut changes = Vec::new();
+    let pips: BTreeSet<(&String, &String)> = c0.pips.iter().chain(c1.pips.iter()).collect();
+    for (to_wire, from_wire) in pips {
+        let pip = (to_wire.to_string(), from_wire.to_string());
+        match (c0.pips.get(to_wire) == Some(from_wire), c1.pips.get(to_wire) == Some(from_wire)) {
+            (false, true) => changes.push(FeatureChange::PipAdded { to_wire: pip.0, from_wire: pip.1 }),
+            (true, false) => changes.push(FeatureChange::PipRemoved { to_wire: pip.0, from_wire: pip.1 }),
+            _ => {}
+        }
+    }
+    let enums: BTreeSet<&String> = c0.enums.keys().chain(c1.enums.keys()).collect();
+    for name in enums {
+        let (o, n) = (c0.enums.get(name), c1.enums.get(name));
+        if o != n {
+            changes.push(FeatureChange::EnumChanged {
+                name: name.to_string(),
+                old: o.cloned(),
+                new: n.cloned(),
+            });
+        }
+    }
+    let words: BTreeSet<&String> = c0.words.keys().chain(c1.words.keys()).collect();
+    for name in words {
+        let (o, n) = (c0.words.get(name), c1.words.get(name));
+        if o != n {
+            let width = o.or(n).unwrap().len();
+            changes.push(FeatureChange::WordChanged {
+                name: name.to_string(),
+                old: o.cloned().unwrap_or_else(|| vec![false; width]),
+                new: n.cloned().unwrap_or_else(|| vec![false; width]),
+            });
+        }
+    }
+    for &(frame, bit) in c0.unknowns.symmetric_difference(&c1.unknowns) {
+        changes.push(FeatureChange::UnknownBit {
+            frame,
+            bit,
+            value: c1.unknowns.contains(&(frame, bit)),
+        });
+    }
+    changes
+}
+
+// Compare two configurations of the same device. Tiles must be up to date
+// with the CRAM (see Chip::cram_to_tiles)
+pub fn diff_chips(db: &mut Database, old: &Chip, new: &Chip) -> Result<ChipDiff, DeviceMismatch> {
+    DeviceMismatch::check(old, new)?;
+    // Chips of the same device can still have been built from different tilegrids
+    let tile_count = std::cmp::max(old.tiles.len(), new.tiles.len());
+    let tile_name = |ch: &Chip, i: usize| ch.tiles.get(i).map(|t| t.name.to_string());
+    if let Some(i) = (0..tile_count).find(|&i| tile_name(old, i) != tile_name(new, i)) {
+        let describe = |ch: &Chip| match tile_name(ch, i) {
+            Some(name) => format!("{} with tile {}", ch.device, name),
+            None => format!("{} with {} tiles", ch.device, ch.tiles.len()),
+        };
+        return Err(DeviceMismatch {
+            expected: describe(old),
+            found: describe(new),
+        });
+    }
+    let mut diff = ChipDiff::default();
+    for (t0, t1) in old.tiles.iter().zip(new.tiles.iter()) {
+        if t0.cram == t1.cram {
+            continue;
+        }
+        let changes = diff_tile(db, t0, t1);
+        if !changes.is_empty() {
+            diff.tiles.push(TileDiff {
+                tile: t1.name.to_string(),
+                tiletype: t1.tiletype.to_string(),
+                changes,
+            });
+        }
+    }
+    // IPs are compared by feature when each side either decodes or doesn't
+    // configure the IP at all; otherwise only raw bytes are meaningful
+    let (d0, d1) = (old.decode_ip(db), new.decode_ip(db));
+    let (ips0, ips1) = (configured_ips(db, old), configured_ips(db, new));
+    let by_feature: BTreeSet<&String> = d0
+        .ips
+        .keys()
+        .chain(d1.ips.keys())
+        .filter(|ip| (d0.ips.contains_key(*ip) || !ips0.contains(*ip)) && (d1.ips.contains_key(*ip) || !ips1.contains(*ip)))
+        .collect();
+    let mut ip_diffs = BTreeMap::<String, IPDiff>::new();
+    let empty = TileConfig::default();
+    for &ip in by_feature.iter() {
+        let features = diff_config(d0.ips.get(ip).unwrap_or(&empty), d1.ips.get(ip).unwrap_or(&empty));
+        if !features.is_empty() {
+            ip_diffs.insert(ip.to_string(), IPDiff {
+                ip: ip.to_string(),
+                features,
+                changes: Vec::new(),
+            });
+        }
+    }
+    let addrs: BTreeSet<u32> = old.ipconfig.keys().chain(new.ipconfig.keys()).cloned().collect();
+    for addr in addrs {
+        let o = *old.ipconfig.get(&addr).unwrap_or(&0x00);
+        let n = *new.ipconfig.get(&addr).unwrap_or(&0x00);
+        if o == n {
+            continue;
+        }
+        let (ip, base) = new
+            .ip_at_address(db, addr)
+            .unwrap_or_else(|| ("UNKNOWN".to_string(), 0));
+        // Bytes rebuilt exactly from the features on both sides are covered by the feature changes
+        if by_feature.contains(&ip) && !d0.unknown.contains_key(&addr) && !d1.unknown.contains_key(&addr) {
+            continue;
+        }
+        let change = IPChange {
+            offset: addr - base,
+            old: o,
+            new: n,
+        };
+        ip_diffs
+            .entry(ip.to_string())
+            .or_insert_with(|| IPDiff {
+                ip,
+                features: Vec::new(),
+                changes: Vec::new(),
+            })
+            .changes
+            .push(change);
+    }
+    diff.ips = ip_diffs.into_values().collect();
+    Ok(diff)
+}
+
+// Names of the IPs with any configuration space set
+fn configured_ips(db: &mut Database, ch: &Chip) -> BTreeSet<String> {
+    ch.ipconfig
+        .keys()
+        .filter_map(|&addr| ch.ip_at_address(db, addr).map(|(ip, _base)| ip))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_database;
+    use crate::fasmparse::ParsedFasm;
+
+    const BASE: &str = "R1C1__PLC.PIP.A0.B0
+R1C1__PLC.SLICEA.MODE.RAMW
+IP_PLL_LLC.CLKOP_EN.ENABLED
+IP_PLL_LLC.DIVOP[9:0] = 10'h2A5
+";
+
+    fn chip(db: &mut Database, fasm: &str) -> Chip {
+        Chip::try_from_fasm(db, &ParsedFasm::parse_str(fasm).unwrap(), Some("TEST")).unwrap()
+    }
+
+    fn word(value: u32, width: usize) -> Vec<bool> {
+        (0..width).map(|i| (value >> i) & 1 == 1).collect()
+    }
+
+    #[test]
+    fn diff_identical() {
+        let mut db = test_database();
+        let (old, new) = (chip(&mut db, BASE), chip(&mut db, BASE));
+        assert!(diff_chips(&mut db, &old, &new).unwrap().is_empty());
+
+        let mut renamed = new.clone();
+        renamed.tiles[0].name = "R9C9:PLC".to_string();
+        assert_eq!(
+            diff_chips(&mut db, &old, &renamed).unwrap_err(),
+            DeviceMismatch {
+                expected: format!("TEST with tile {}", old.tiles[0].name),
+                found: "TEST with tile R9C9:PLC".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn diff_tile_feature() {
+        let mut db = test_database();
+        let old = chip(&mut db, BASE);
+        let new = chip(&mut db, &BASE.replace("SLICEA.MODE.RAMW", "SLICEA.MODE.CCU2"));
+        assert_eq!(
+            diff_chips(&mut db, &old, &new).unwrap(),
+            ChipDiff {
+                tiles: vec![TileDiff {
+                    tile: "R1C1:PLC".to_string(),
+                    tiletype: "PLC".to_string(),
+                    changes: vec![FeatureChange::EnumChanged {
+                        name: "SLICEA.MODE".to_string(),
+                        old: Some("RAMW".to_string()),
+                        new: Some("CCU2".to_string()),
+                    }],
+                }],
+                ips: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn diff_ip() {
+        let mut db = test_database();
+        let old = chip(&mut db, BASE);
+        // A changed word, and a byte outside of any PLL feature
+        let new = chip(
+            &mut db,
+            &(BASE.replace("10'h2A5", "10'h2A4") + "IP_UNKNOWN.0x0E000050[7:0] = 8'h81\n"),
+        );
+        assert_eq!(
+            diff_chips(&mut db, &old, &new).unwrap(),
+            ChipDiff {
+                tiles: vec![],
+                ips: vec![IPDiff {
+                    ip: "PLL_LLC".to_string(),
+                    features: vec![FeatureChange::WordChanged {
+                        name: "DIVOP".to_string(),
+                        old: word(0x2A5, 10),
+                        new: word(0x2A4, 10),
+                    }],
+                    changes: vec![IPChange {
+                        offset: 0x50,
+                        old: 0x00,
+                        new: 0x81,
+                    }],
+                }],
+            }
+        );
+    }
+}
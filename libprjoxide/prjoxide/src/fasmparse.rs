@@ -1,28 +1,148 @@
-use std::collections::BTreeMap;
-use std::convert::TryInto;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::File;
-use std::io::*;
+use std::io::{BufRead, BufReader, Write};
 
 use rug::Integer;
 
 /*
-Parser for the subset of FASM that Oxide cares about
+Parser for FASM
 
-Note that this does some preprocessing on features to speed up
-downstream usage.
+Each line is an optional feature, an optional set of annotations and an
+optional comment:
+
+    TILE.FEATURE[msb:lsb] = 4'b10x1 { key="value" } # comment
+
+Every feature is kept as written in `features`, with its source span. In
+addition, the features are preprocessed to speed up downstream usage.
 
 The first part of a feature, split by '.' is considered the tile
 if 'PIP' follows it is a pip of form to_wire.from_wire
+if 'UNKNOWN' follows it is an unknown bit of form frame.bit
+if 'ALWAYS_ON' follows it sets or clears the tile's always-on bits
 if it is vector style it is a word
 otherwise it is an enum
 
+Setting a pip, enum or unknown bit to 0 is an explicit clear. 'x' digits in
+a word value leave those bits unspecified. Annotations on a line of their
+own are global attributes.
+
 */
 
+// Location of something in the source: a 1-based line number, and 0-based
+// byte columns [start, end) within that line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FasmSpan {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for FasmSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.start + 1)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FasmErrorKind {
+    Io(String),
+    // Something else was found where this was expected
+    Expected(&'static str),
+    UnterminatedString,
+    InvalidBase(char),
+    InvalidDigit { digit: char, base: u32 },
+    // Feature address of the form [lsb:msb]
+    InvalidRange { msb: u32, lsb: u32 },
+    // Value doesn't fit in the width of the feature or value
+    ValueTooWide { width: u32 },
+    // UNKNOWN feature that isn't UNKNOWN.frame.bit
+    InvalidUnknownBit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FasmError {
+    pub span: FasmSpan,
+    pub kind: FasmErrorKind,
+}
+
+impl FasmError {
+    fn new(span: FasmSpan, kind: FasmErrorKind) -> FasmError {
+        FasmError { span, kind }
+    }
+}
+
+impl fmt::Display for FasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let FasmErrorKind::Io(e) = &self.kind {
+            return write!(f, "{}", e);
+        }
+        write!(f, "{}: ", self.span)?;
+        match &self.kind {
+            FasmErrorKind::Io(_) => unreachable!(),
+            FasmErrorKind::Expected(what) => write!(f, "expected {}", what),
+            FasmErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            FasmErrorKind::InvalidBase(base) => write!(f, "unsupported base '{}", base),
+            FasmErrorKind::InvalidDigit { digit, base } => {
+                write!(f, "invalid digit '{}' for base {}", digit, base)
+            }
+            FasmErrorKind::InvalidRange { msb, lsb } => {
+                write!(f, "invalid feature address [{}:{}]", msb, lsb)
+            }
+            FasmErrorKind::ValueTooWide { width } => write!(f, "value does not fit in {} bits", width),
+            FasmErrorKind::InvalidUnknownBit => write!(f, "expected UNKNOWN.<frame>.<bit>"),
+        }
+    }
+}
+
+impl std::error::Error for FasmError {}
+
+impl From<FasmError> for std::io::Error {
+    fn from(e: FasmError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+// A value assigned to a feature. Bits given as 'x' are clear in `mask`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FasmValue {
+    pub value: Integer,
+    pub mask: Integer,
+}
+
+// A feature as written in the source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FasmFeature {
+    // Full dotted feature name
+    pub name: String,
+    // Feature address as (msb, lsb), if any
+    pub address: Option<(u32, u32)>,
+    // Value, with the LSB being the bit at `lsb`; 1 if no value was given
+    pub value: FasmValue,
+    pub annotations: Vec<(String, String)>,
+    pub span: FasmSpan,
+}
+
 pub struct FasmTile {
     pub pips: BTreeMap<String, String>,
     pub enums: BTreeMap<String, String>,
     pub words: BTreeMap<String, Integer>,
     pub unknowns: Vec<(usize, usize)>,
+    // Bits of each word that were given, rather than left as 'x' or unspecified
+    pub word_masks: BTreeMap<String, Integer>,
+    // Explicitly cleared pips (to_wire, from_wire), enum options (enum, option)
+    // and unknown bits
+    pub cleared_pips: BTreeSet<(String, String)>,
+    pub cleared_enums: BTreeSet<(String, String)>,
+    pub cleared_unknowns: Vec<(usize, usize)>,
+    // Explicit ALWAYS_ON setting; always-on bits are set by default
+    pub always_on: Option<bool>,
+    // Source spans of the first feature in the tile, and of each pip (keyed by
+    // sink wire), enum and word
+    pub span: FasmSpan,
+    pub pip_spans: BTreeMap<String, FasmSpan>,
+    pub enum_spans: BTreeMap<String, FasmSpan>,
+    pub word_spans: BTreeMap<String, FasmSpan>,
 }
 
 impl FasmTile {
@@ -32,212 +152,513 @@ impl FasmTile {
             enums: BTreeMap::new(),
             words: BTreeMap::new(),
             unknowns: Vec::new(),
+            word_masks: BTreeMap::new(),
+            cleared_pips: BTreeSet::new(),
+            cleared_enums: BTreeSet::new(),
+            cleared_unknowns: Vec::new(),
+            always_on: None,
+            span: FasmSpan::default(),
+            pip_spans: BTreeMap::new(),
+            enum_spans: BTreeMap::new(),
+            word_spans: BTreeMap::new(),
         }
     }
+    // Source line of a feature, falling back to the first line of the tile
+    pub fn pip_line(&self, to_wire: &str) -> usize {
+        self.pip_spans.get(to_wire).unwrap_or(&self.span).line
+    }
+    pub fn enum_line(&self, name: &str) -> usize {
+        self.enum_spans.get(name).unwrap_or(&self.span).line
+    }
+    pub fn word_line(&self, name: &str) -> usize {
+        self.word_spans.get(name).unwrap_or(&self.span).line
+    }
 }
 
 pub struct ParsedFasm {
     pub attrs: Vec<(String, String)>,
+    // Source span of each attribute
+    pub attr_spans: Vec<FasmSpan>,
+    // All features, in source order
+    pub features: Vec<FasmFeature>,
     pub tiles: BTreeMap<String, FasmTile>,
 }
 
+// Cursor over a single line of FASM
+struct LineParser<'a> {
+    line: &'a str,
+    lineno: usize,
+    pos: usize,
+}
+
+impl<'a> LineParser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+    fn span_from(&self, start: usize) -> FasmSpan {
+        FasmSpan {
+            line: self.lineno,
+            start,
+            end: self.pos,
+        }
+    }
+    fn error(&self, kind: FasmErrorKind) -> FasmError {
+        FasmError::new(
+            FasmSpan {
+                line: self.lineno,
+                start: self.pos,
+                end: self.pos + self.rest().chars().next().map_or(0, |c| c.len_utf8()),
+            },
+            kind,
+        )
+    }
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    // Whether the rest of the line is empty or a comment
+    fn at_end(&self) -> bool {
+        let rest = self.rest();
+        rest.is_empty() || rest.starts_with('#')
+    }
+    fn check_token(&mut self, tok: &str) -> bool {
+        if self.rest().starts_with(tok) {
+            self.pos += tok.len();
+            true
+        } else {
+            false
+        }
+    }
+    fn expect_token(&mut self, tok: &str, what: &'static str) -> Result<(), FasmError> {
+        if self.check_token(tok) {
+            Ok(())
+        } else {
+            Err(self.error(FasmErrorKind::Expected(what)))
+        }
+    }
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let end_index = rest.find(|c: char| !pred(c)).unwrap_or(rest.len());
+        self.pos += end_index;
+        &rest[0..end_index]
+    }
+    fn get_ident(&mut self) -> Result<&'a str, FasmError> {
+        let ident = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if ident.is_empty() {
+            return Err(self.error(FasmErrorKind::Expected("identifier")));
+        }
+        Ok(ident)
+    }
+    fn get_integer(&mut self) -> Result<u32, FasmError> {
+        let start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        if digits.is_empty() {
+            return Err(self.error(FasmErrorKind::Expected("integer")));
+        }
+        digits
+            .parse::<u32>()
+            .map_err(|_| FasmError::new(self.span_from(start), FasmErrorKind::ValueTooWide { width: 32 }))
+    }
+    // Feature name, as a list of '.' separated identifiers
+    fn get_feature(&mut self) -> Result<Vec<&'a str>, FasmError> {
+        let mut parts = vec![self.get_ident()?];
+        while self.check_token(".") {
+            parts.push(self.get_ident()?);
+        }
+        Ok(parts)
+    }
+    // Feature address [msb:lsb] or [bit], returned as (msb, lsb)
+    fn get_address(&mut self) -> Result<(u32, u32), FasmError> {
+        let start = self.pos;
+        self.expect_token("[", "'['")?;
+        self.skip_whitespace();
+        let msb = self.get_integer()?;
+        self.skip_whitespace();
+        let lsb = if self.check_token(":") {
+            self.skip_whitespace();
+            self.get_integer()?
+        } else {
+            msb
+        };
+        self.skip_whitespace();
+        self.expect_token("]", "']'")?;
+        if msb < lsb {
+            return Err(FasmError::new(self.span_from(start), FasmErrorKind::InvalidRange { msb, lsb }));
+        }
+        Ok((msb, lsb))
+    }
+    // Plain decimal or Verilog-style value, with the width if one was given
+    fn get_value(&mut self) -> Result<(Digits, Option<u32>), FasmError> {
+        let start = self.pos;
+        let width_or_value = self.take_while(|c| c.is_ascii_digit() || c == '_');
+        self.skip_whitespace();
+        if !self.check_token("'") {
+            if width_or_value.is_empty() {
+                return Err(self.error(FasmErrorKind::Expected("value")));
+            }
+            let value = parse_digits(width_or_value, 10).map_err(|d| {
+                FasmError::new(self.span_from(start), FasmErrorKind::InvalidDigit { digit: d, base: 10 })
+            })?;
+            return Ok((value, None));
+        }
+        let width = match width_or_value {
+            "" => None,
+            w => Some(w.replace('_', "").parse::<u32>().map_err(|_| {
+                FasmError::new(self.span_from(start), FasmErrorKind::ValueTooWide { width: 32 })
+            })?),
+        };
+        let base = match self.rest().chars().next() {
+            Some('b') | Some('B') => 2,
+            Some('o') | Some('O') => 8,
+            Some('d') | Some('D') => 10,
+            Some('h') | Some('H') => 16,
+            Some(c) => return Err(self.error(FasmErrorKind::InvalidBase(c))),
+            None => return Err(self.error(FasmErrorKind::Expected("base"))),
+        };
+        self.pos += 1;
+        self.skip_whitespace();
+        let digits_start = self.pos;
+        let digits = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '?');
+        if digits.is_empty() {
+            return Err(self.error(FasmErrorKind::Expected("digits")));
+        }
+        let parsed = parse_digits(digits, base).map_err(|digit| {
+            FasmError::new(self.span_from(digits_start), FasmErrorKind::InvalidDigit { digit, base })
+        })?;
+        if let Some(w) = width {
+            if parsed.value.significant_bits() > w {
+                return Err(FasmError::new(self.span_from(start), FasmErrorKind::ValueTooWide { width: w }));
+            }
+        }
+        Ok((parsed, width))
+    }
+    // Quoted string, with \" and \\ escapes
+    fn get_string(&mut self) -> Result<String, FasmError> {
+        let start = self.pos;
+        self.expect_token("\"", "'\"'")?;
+        let mut val = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(val);
+                }
+                '\\' => match chars.next() {
+                    Some((_, e)) => val.push(e),
+                    None => break,
+                },
+                _ => val.push(c),
+            }
+        }
+        self.pos = self.line.len();
+        Err(FasmError::new(self.span_from(start), FasmErrorKind::UnterminatedString))
+    }
+    // Annotations of the form { key = "value", ... }
+    fn get_annotations(&mut self) -> Result<Vec<(String, String, FasmSpan)>, FasmError> {
+        let mut annotations = Vec::new();
+        self.expect_token("{", "'{'")?;
+        self.skip_whitespace();
+        if self.check_token("}") {
+            return Ok(annotations);
+        }
+        loop {
+            let start = self.pos;
+            let key = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+            if key.is_empty() {
+                return Err(self.error(FasmErrorKind::Expected("annotation name")));
+            }
+            self.skip_whitespace();
+            self.expect_token("=", "'='")?;
+            self.skip_whitespace();
+            let value = if self.rest().starts_with('"') {
+                self.get_string()?
+            } else {
+                // Not strictly FASM, but accept unquoted values too
+                self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+                    .to_string()
+            };
+            annotations.push((key.to_string(), value, self.span_from(start)));
+            self.skip_whitespace();
+            if self.check_token("}") {
+                return Ok(annotations);
+            }
+            self.expect_token(",", "',' or '}'")?;
+            self.skip_whitespace();
+        }
+    }
+}
+
+// Digits of a value; bits given as 'x' are set in `unknown`. As in Verilog, a
+// leading 'x' extends to the full width of the value
+struct Digits {
+    value: Integer,
+    unknown: Integer,
+    bits: u32,
+    unknown_msb: bool,
+}
+
+// Parse the digits of a value, returning the invalid digit on error
+fn parse_digits(digits: &str, base: u32) -> std::result::Result<Digits, char> {
+    let mut value = Integer::new();
+    let mut unknown = Integer::new();
+    if base == 10 {
+        let digits = digits.replace('_', "");
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_digit()) {
+            return Err(c);
+        }
+        value = Integer::from(Integer::parse_radix(&digits, 10).map_err(|_| '_')?);
+        let bits = value.significant_bits();
+        return Ok(Digits { value, unknown, bits, unknown_msb: false });
+    }
+    let bits_per_digit = base.trailing_zeros();
+    let mut bit = 0;
+    let mut unknown_msb = false;
+    for c in digits.chars().rev() {
+        if c == '_' {
+            continue;
+        }
+        let is_unknown = matches!(c, 'x' | 'X' | 'z' | 'Z' | '?');
+        let d = match c.to_digit(base) {
+            Some(d) => d,
+            None if is_unknown => 0,
+            None => return Err(c),
+        };
+        for i in 0..bits_per_digit {
+            value.set_bit(bit + i, (d >> i) & 1 == 1);
+            unknown.set_bit(bit + i, is_unknown);
+        }
+        bit += bits_per_digit;
+        unknown_msb = is_unknown;
+    }
+    Ok(Digits { value, unknown, bits: bit, unknown_msb })
+}
+
+// Integer with the low `n` bits set
+fn ones(n: u32) -> Integer {
+    let mut x = Integer::new();
+    for i in 0..n {
+        x.set_bit(i, true);
+    }
+    x
+}
+
 impl ParsedFasm {
-    pub fn parse(filename: &str) -> Result<ParsedFasm> {
+    pub fn parse(filename: &str) -> Result<ParsedFasm, FasmError> {
+        let file = File::open(filename).map_err(|e| {
+            FasmError::new(FasmSpan::default(), FasmErrorKind::Io(format!("failed to open {}: {}", filename, e)))
+        })?;
+        ParsedFasm::parse_reader(&mut BufReader::new(file))
+    }
+
+    // Parse FASM held in a string
+    pub fn parse_str(fasm: &str) -> Result<ParsedFasm, FasmError> {
+        ParsedFasm::parse_reader(&mut fasm.as_bytes())
+    }
+
+    pub fn parse_reader(reader: &mut dyn BufRead) -> Result<ParsedFasm, FasmError> {
         let mut p = ParsedFasm {
             attrs: Vec::new(),
+            attr_spans: Vec::new(),
+            features: Vec::new(),
             tiles: BTreeMap::new(),
         };
-        let file = File::open(filename)?;
-        let reader = BufReader::new(file);
         for (lineno, line) in reader.lines().enumerate() {
-            let l: String = line?;
-            let first_nonblank = l.find(|c: char| !c.is_whitespace());
-            if first_nonblank == None {
-                continue;
-            }
-            let first_nonblank = first_nonblank.unwrap();
-            let comment_start = l.find(|c: char| c == '#').unwrap_or(l.len());
-            if first_nonblank >= comment_start {
+            let l = line.map_err(|e| {
+                FasmError::new(
+                    FasmSpan { line: lineno + 1, start: 0, end: 0 },
+                    FasmErrorKind::Io(e.to_string()),
+                )
+            })?;
+            let mut lp = LineParser {
+                line: &l,
+                lineno: lineno + 1,
+                pos: 0,
+            };
+            lp.skip_whitespace();
+            if lp.at_end() {
                 continue;
             }
-            let mut buf = &l[first_nonblank..comment_start];
-
-            let get_ident = |parsebuf: &mut &str| -> String {
-                let end_index = parsebuf
-                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
-                    .unwrap_or(parsebuf.len());
-                let token = &parsebuf[0..end_index];
-                *parsebuf = &parsebuf[end_index..];
-                token.to_string()
-            };
-            let get_attr_key = |parsebuf: &mut &str| -> String {
-                let end_index = parsebuf
-                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
-                    .unwrap_or(parsebuf.len());
-                let token = &parsebuf[0..end_index];
-                *parsebuf = &parsebuf[end_index..];
-                token.to_string()
-            };
-            let assert_token = |parsebuf: &mut &str, tok: &str| {
-                if tok.len() > parsebuf.len() || &parsebuf[0..tok.len()] != tok {
-                    panic!("expected token {} on line {}", tok, lineno + 1);
-                }
-                *parsebuf = &&parsebuf[tok.len()..];
-            };
-            let check_token = |parsebuf: &mut &str, tok: &str| -> bool {
-                if tok.len() > parsebuf.len() || &parsebuf[0..tok.len()] != tok {
-                    false
-                } else {
-                    *parsebuf = &parsebuf[tok.len()..];
-                    true
-                }
-            };
-            let skip_whitespace = |parsebuf: &mut &str| {
-                let end_index = parsebuf
-                    .find(|c: char| !c.is_whitespace())
-                    .unwrap_or(parsebuf.len());
-                *parsebuf = &parsebuf[end_index..];
-            };
-            let get_integer = |parsebuf: &mut &str| -> i32 {
-                let end_index = parsebuf
-                    .find(|c: char| !c.is_ascii_digit())
-                    .unwrap_or(parsebuf.len());
-                if end_index == 0 {
-                    panic!("expected numeric value on line {}", lineno + 1);
+            if lp.rest().starts_with('{') {
+                // Annotations on their own are global attributes
+                for (key, value, span) in lp.get_annotations()? {
+                    p.attrs.push((key, value));
+                    p.attr_spans.push(span);
                 }
-                let val = parsebuf[0..end_index].parse::<i32>().unwrap();
-                *parsebuf = &parsebuf[end_index..];
-                val
-            };
-            let get_char = |parsebuf: &mut &str| -> char {
-                let ch = parsebuf.chars().next().unwrap();
-                *parsebuf = &parsebuf[1..];
-                ch
-            };
-            let get_value = |parsebuf: &mut &str| -> Integer {
-                skip_whitespace(parsebuf);
-                let width_or_value = get_integer(parsebuf);
-                skip_whitespace(parsebuf);
-                if !check_token(parsebuf, "'") {
-                    return Integer::from(width_or_value);
+            } else {
+                let feature = ParsedFasm::parse_feature(&mut lp)?;
+                p.add_feature(&feature)?;
+                p.features.push(feature);
+                // Older versions of unpack wrote IP words with a trailing ';'
+                lp.check_token(";");
+            }
+            lp.skip_whitespace();
+            if !lp.at_end() {
+                return Err(lp.error(FasmErrorKind::Expected("end of line")));
+            }
+        }
+        Ok(p)
+    }
+
+    fn parse_feature(lp: &mut LineParser) -> Result<FasmFeature, FasmError> {
+        let start = lp.pos;
+        let name = lp.get_feature()?.join(".");
+        let address = if lp.rest().starts_with('[') {
+            Some(lp.get_address()?)
+        } else {
+            None
+        };
+        let span = lp.span_from(start);
+        let width = address.map_or(1, |(msb, lsb)| msb - lsb + 1);
+        lp.skip_whitespace();
+        let value = if lp.check_token("=") {
+            lp.skip_whitespace();
+            let value_start = lp.pos;
+            let (value, _) = lp.get_value()?;
+            if value.value.significant_bits() > width {
+                return Err(FasmError::new(lp.span_from(value_start), FasmErrorKind::ValueTooWide { width }));
+            }
+            // Bits beyond the digits given are zero, rather than unspecified
+            let mut mask = ones(width);
+            for i in 0..width {
+                if value.unknown.get_bit(i) || (value.unknown_msb && i >= value.bits) {
+                    mask.set_bit(i, false);
                 }
-                skip_whitespace(parsebuf);
-                let base = get_char(parsebuf);
-                let digits = get_ident(parsebuf);
-                Integer::from(
-                    match base {
-                        'b' => Integer::parse_radix(&digits, 2),
-                        'o' => Integer::parse_radix(&digits, 8),
-                        'd' => Integer::parse_radix(&digits, 10),
-                        'h' => Integer::parse_radix(&digits, 16),
-                        _ => panic!("unsupported base '{} on line {}", base, lineno + 1),
-                    }
-                    .unwrap(),
-                )
-            };
-            let get_attr_value = |parsebuf: &mut &str| -> String {
-                if check_token(parsebuf, "\"") {
-                    // String
-                    let end_index = parsebuf.find('"').unwrap();
-                    let val = &parsebuf[0..end_index];
-                    *parsebuf = &parsebuf[end_index + 1..];
-                    val.to_string()
-                } else {
-                    // Not string
-                    get_attr_key(parsebuf)
+            }
+            FasmValue {
+                value: value.value,
+                mask,
+            }
+        } else {
+            FasmValue {
+                value: Integer::from(1),
+                mask: ones(width),
+            }
+        };
+        lp.skip_whitespace();
+        let annotations = if lp.rest().starts_with('{') {
+            lp.get_annotations()?.into_iter().map(|(k, v, _)| (k, v)).collect()
+        } else {
+            Vec::new()
+        };
+        Ok(FasmFeature {
+            name,
+            address,
+            value,
+            annotations,
+            span,
+        })
+    }
+
+    // Add a feature to the preprocessed per-tile data
+    fn add_feature(&mut self, f: &FasmFeature) -> Result<(), FasmError> {
+        let parts: Vec<&str> = f.name.split('.').collect();
+        if parts.len() < 2 {
+            return Err(FasmError::new(f.span, FasmErrorKind::Expected("TILE.FEATURE")));
+        }
+        let tilename = parts[0].replace("__", ":");
+        let tile_data = self.tiles.entry(tilename).or_insert_with(|| FasmTile {
+            span: f.span,
+            ..FasmTile::new()
+        });
+        let set = !f.value.value.is_zero();
+        let specified = !f.value.mask.is_zero();
+        if parts.len() >= 2 && parts[1] == "PIP" && f.address.is_none() && parts.len() == 4 {
+            // It's a pip
+            let to_wire = parts[2].replace("__", ":");
+            let from_wire = parts[3].replace("__", ":");
+            if !specified {
+                return Ok(());
+            }
+            tile_data.pip_spans.insert(to_wire.to_string(), f.span);
+            if set {
+                tile_data.cleared_pips.remove(&(to_wire.to_string(), from_wire.to_string()));
+                tile_data.pips.insert(to_wire, from_wire);
+            } else {
+                if tile_data.pips.get(&to_wire) == Some(&from_wire) {
+                    tile_data.pips.remove(&to_wire);
                 }
+                tile_data.cleared_pips.insert((to_wire, from_wire));
+            }
+        } else if parts.len() >= 2 && parts[1] == "UNKNOWN" {
+            let bit = match (parts.len(), f.address) {
+                (4, None) => parts[2].parse::<usize>().ok().zip(parts[3].parse::<usize>().ok()),
+                _ => None,
             };
-            if check_token(&mut buf, "{") {
-                skip_whitespace(&mut buf);
-                while !check_token(&mut buf, "}") {
-                    let key = get_attr_key(&mut buf);
-                    skip_whitespace(&mut buf);
-                    assert_token(&mut buf, "=");
-                    skip_whitespace(&mut buf);
-                    p.attrs.push((key, get_attr_value(&mut buf)));
-                    skip_whitespace(&mut buf);
-                    if !check_token(&mut buf, ",") {
-                        assert_token(&mut buf, "}");
-                        break;
-                    }
+            let bit = bit.ok_or_else(|| FasmError::new(f.span, FasmErrorKind::InvalidUnknownBit))?;
+            if !specified {
+                return Ok(());
+            }
+            tile_data.unknowns.retain(|b| *b != bit);
+            tile_data.cleared_unknowns.retain(|b| *b != bit);
+            if set {
+                tile_data.unknowns.push(bit);
+            } else {
+                tile_data.cleared_unknowns.push(bit);
+            }
+        } else if parts.len() == 2 && parts[1] == "ALWAYS_ON" && f.address.is_none() {
+            if specified {
+                tile_data.always_on = Some(set);
+            }
+        } else if let Some((msb, lsb)) = f.address {
+            // Word style setting
+            let key = parts[1..].join(".");
+            tile_data.word_spans.entry(key.to_string()).or_insert(f.span);
+            let dest = tile_data
+                .words
+                .entry(key.to_string())
+                .or_insert_with(|| Integer::from(0));
+            let dest_mask = tile_data
+                .word_masks
+                .entry(key)
+                .or_insert_with(|| Integer::from(0));
+            for i in 0..=(msb - lsb) {
+                if f.value.mask.get_bit(i) {
+                    dest.set_bit(lsb + i, f.value.value.get_bit(i));
+                    dest_mask.set_bit(lsb + i, true);
                 }
+            }
+        } else {
+            // Enum style setting
+            let key = parts[1..parts.len() - 1].join(".");
+            let value = parts[parts.len() - 1].to_string();
+            if !specified {
+                return Ok(());
+            }
+            tile_data.enum_spans.insert(key.to_string(), f.span);
+            if set {
+                tile_data.cleared_enums.remove(&(key.to_string(), value.to_string()));
+                tile_data.enums.insert(key, value);
             } else {
-                let tilename = get_ident(&mut buf).replace("__", ":");
-                let tile_data = p.tiles.entry(tilename).or_insert_with(FasmTile::new);
-                assert_token(&mut buf, ".");
-                if check_token(&mut buf, "PIP.") {
-                    // It's a pip
-                    let to_wire = get_ident(&mut buf).replace("__", ":");
-                    assert_token(&mut buf, ".");
-                    let from_wire = get_ident(&mut buf).replace("__", ":");
-                    tile_data.pips.insert(to_wire, from_wire);
-                } else if check_token(&mut buf, "UNKNOWN.") {
-                    let frame: usize = get_integer(&mut buf).try_into().unwrap();
-                    assert_token(&mut buf, ".");
-                    let bit: usize = get_integer(&mut buf).try_into().unwrap();
-                    tile_data.unknowns.push((frame, bit));
-                } else {
-                    let mut feature_split = Vec::new();
-                    loop {
-                        feature_split.push(get_ident(&mut buf));
-                        if !check_token(&mut buf, ".") {
-                            break;
-                        }
-                    }
-                    skip_whitespace(&mut buf);
-                    if check_token(&mut buf, "[") {
-                        skip_whitespace(&mut buf);
-                        // Word style setting
-                        let key = feature_split.join(".");
-                        let end_bit: u32 = get_integer(&mut buf).try_into().unwrap();
-                        skip_whitespace(&mut buf);
-                        let mut start_bit = end_bit;
-                        skip_whitespace(&mut buf);
-                        if check_token(&mut buf, ":") {
-                            skip_whitespace(&mut buf);
-                            start_bit = get_integer(&mut buf).try_into().unwrap();
-                            skip_whitespace(&mut buf);
-                        }
-                        assert!(end_bit >= start_bit);
-                        assert_token(&mut buf, "]");
-                        skip_whitespace(&mut buf);
-                        let value = if check_token(&mut buf, "=") {
-                            skip_whitespace(&mut buf);
-                            get_value(&mut buf)
-                        } else {
-                            Integer::from(1)
-                        };
-                        let dest = tile_data
-                            .words
-                            .entry(key)
-                            .or_insert_with(|| Integer::from(0));
-                        let count: u32 = (end_bit - start_bit) + 1;
-                        for i in 0..count {
-                            dest.set_bit(start_bit + i, value.get_bit(i));
-                        }
-                    } else {
-                        // Enum style setting
-                        let key = feature_split[0..feature_split.len() - 1].join(".");
-                        let value = &feature_split[feature_split.len() - 1];
-                        tile_data.enums.insert(key, value.to_string());
-                    }
+                if tile_data.enums.get(&key) == Some(&value) {
+                    tile_data.enums.remove(&key);
                 }
+                tile_data.cleared_enums.insert((key, value));
             }
         }
-        Ok(p)
+        Ok(())
     }
 
-    pub fn dump(&self, out: &mut dyn Write) -> Result<()> {
+    pub fn dump(&self, out: &mut dyn Write) -> std::io::Result<()> {
         for (akey, aval) in self.attrs.iter() {
-            writeln!(out, "{{ {}=\"{}\" }}", akey, aval)?;
+            writeln!(out, "{{ {}=\"{}\" }}", akey, aval.replace('\\', "\\\\").replace('"', "\\\""))?;
         }
         for (tile, tdata) in self.tiles.iter() {
             for (to_wire, from_wire) in tdata.pips.iter() {
                 writeln!(out, "{}.PIP.{}.{}", tile, to_wire, from_wire)?;
             }
+            for (to_wire, from_wire) in tdata.cleared_pips.iter() {
+                writeln!(out, "{}.PIP.{}.{} = 0", tile, to_wire, from_wire)?;
+            }
             for (name, opt) in tdata.enums.iter() {
                 writeln!(out, "{}.{}.{}", tile, name, opt)?;
             }
+            for (name, opt) in tdata.cleared_enums.iter() {
+                writeln!(out, "{}.{}.{} = 0", tile, name, opt)?;
+            }
+            if let Some(aon) = tdata.always_on {
+                writeln!(out, "{}.ALWAYS_ON = {}", tile, aon as u8)?;
+            }
             for (name, val) in tdata.words.iter() {
                 if val.significant_bits() > 0 {
                     writeln!(
@@ -252,9 +673,92 @@ impl ParsedFasm {
                 }
             }
             if !tdata.pips.is_empty() || !tdata.enums.is_empty() || !tdata.words.is_empty() {
-                writeln!(out, "")?;
+                writeln!(out)?;
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_edge_cases() {
+        let fasm = concat!(
+            "{ oxide.device=\"LIFCL-40\", oxide.meta=\"a \\\"b\\\" \\\\c\" }\n",
+            "\n",
+            "  # comment only\n",
+            "R1C1.WORD[7:0] = 8'b1x0x_0110 { note=\"}#\" } # comment\n",
+            "R1C1.WIDE[7:0] = 8'hx5\n",
+            "R1C1.MODE.OPT\n",
+            "R1C1.MODE.OTHER = 0\n",
+            "IP_X.INIT[3:0] = 4'hA;\n",
+        );
+        let p = ParsedFasm::parse_str(fasm).unwrap();
+        assert_eq!(
+            p.attrs,
+            vec![
+                ("oxide.device".to_string(), "LIFCL-40".to_string()),
+                ("oxide.meta".to_string(), "a \"b\" \\c".to_string()),
+            ]
+        );
+        assert_eq!(p.attr_spans[1], FasmSpan { line: 1, start: 27, end: 51 });
+        assert_eq!(p.features.len(), 5);
+        assert_eq!(p.features[0].annotations, vec![("note".to_string(), "}#".to_string())]);
+        assert_eq!(p.features[0].span, FasmSpan { line: 4, start: 0, end: 14 });
+
+        // 'x' digits leave bits unspecified, and a leading 'x' extends to the MSB
+        let t = &p.tiles["R1C1"];
+        let word = |name: &str| (0..8).map(|i| (t.words[name].get_bit(i), t.word_masks[name].get_bit(i))).collect::<Vec<_>>();
+        let (u, z, o) = ((false, false), (false, true), (true, true));
+        assert_eq!(word("WORD"), vec![z, o, o, z, u, z, u, o]);
+        assert_eq!(word("WIDE"), vec![o, z, o, z, u, u, u, u]);
+        assert_eq!(t.enums["MODE"], "OPT");
+        assert!(t.cleared_enums.contains(&("MODE".to_string(), "OTHER".to_string())));
+
+        // Trailing ';' written by older versions of unpack
+        let ip = &p.tiles["IP_X"];
+        assert_eq!((0..4).map(|i| ip.words["INIT"].get_bit(i)).collect::<Vec<_>>(), vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn parse_error_spans() {
+        let err = |fasm: &str| ParsedFasm::parse_str(fasm).err().unwrap();
+        assert_eq!(
+            err("A.B\nR1C1.W[3:0] = 4'b10201\n"),
+            FasmError::new(FasmSpan { line: 2, start: 17, end: 22 }, FasmErrorKind::InvalidDigit { digit: '2', base: 2 })
+        );
+        assert_eq!(
+            err("R1C1.W[0:3] = 1\n"),
+            FasmError::new(FasmSpan { line: 1, start: 6, end: 11 }, FasmErrorKind::InvalidRange { msb: 0, lsb: 3 })
+        );
+        assert_eq!(
+            err("R1C1.W[1:0] = 3'b100\n"),
+            FasmError::new(FasmSpan { line: 1, start: 14, end: 20 }, FasmErrorKind::ValueTooWide { width: 2 })
+        );
+        assert_eq!(
+            err("{ a=\"b }\n"),
+            FasmError::new(FasmSpan { line: 1, start: 4, end: 8 }, FasmErrorKind::UnterminatedString)
+        );
+        assert_eq!(
+            err("R1C1.A.B;;\n"),
+            FasmError::new(FasmSpan { line: 1, start: 9, end: 10 }, FasmErrorKind::Expected("end of line"))
+        );
+        assert_eq!(
+            err("R1C1.UNKNOWN.1\n"),
+            FasmError::new(FasmSpan { line: 1, start: 0, end: 14 }, FasmErrorKind::InvalidUnknownBit)
+        );
+        // A feature needs at least a tile and a name
+        assert_eq!(
+            err("  FOO\n"),
+            FasmError::new(FasmSpan { line: 1, start: 2, end: 5 }, FasmErrorKind::Expected("TILE.FEATURE"))
+        );
+        assert_eq!(
+            err("FOO[3:0] = 4'h1\n"),
+            FasmError::new(FasmSpan { line: 1, start: 0, end: 8 }, FasmErrorKind::Expected("TILE.FEATURE"))
+        );
+        assert_eq!(err("R1C1.W[1:0] = # x\n").to_string(), "line 1, column 15: expected value");
+    }
+}
@@ -18,14 +18,17 @@ pub mod bitstream;
 pub mod chip;
 pub mod database;
 pub mod database_html;
+pub mod diff;
 pub mod docs;
 pub mod fasmparse;
 pub mod fuzz;
 pub mod ipfuzz;
+pub mod multiboot;
 pub mod nodecheck;
 pub mod wires;
 pub mod pip_classes;
 pub mod sites;
+pub mod svf;
 pub mod interchange_gen {
 	pub mod routing_graph;
     pub mod writer;
@@ -0,0 +1,396 @@
+use crate::bitstream::*;
+use crate::database::Database;
+
+use std::fmt;
+use std::io::Write;
+
+// Multi-boot SPI flash images
+//
+// Flash address 0 holds a jump bitstream (SPI_MODE then JUMP) pointing at the
+// primary image. All unused flash is left erased (0xFF).
+//
+// How the configuration engine finds a golden image after a failed primary
+// load, or further primary images (whether from a fixed address, a second
+// jump, or a setting in the primary bitstream) hasn't been established from
+// Radiant output. Rather than build images that won't fall back as expected,
+// a golden image or more than one primary image is rejected for now.
+
+// SPI flash read command used by the configuration engine after a jump
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpiReadMode {
+    Standard,
+    Fast,
+    Dual,
+    Quad,
+}
+
+impl SpiReadMode {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            SpiReadMode::Standard => 0x03,
+            SpiReadMode::Fast => 0x0B,
+            SpiReadMode::Dual => 0x3B,
+            SpiReadMode::Quad => 0x6B,
+        }
+    }
+    pub fn from_opcode(opcode: u8) -> Option<SpiReadMode> {
+        match opcode {
+            0x03 => Some(SpiReadMode::Standard),
+            0x0B => Some(SpiReadMode::Fast),
+            0x3B => Some(SpiReadMode::Dual),
+            0x6B => Some(SpiReadMode::Quad),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpiReadMode::Standard => "standard",
+            SpiReadMode::Fast => "fast",
+            SpiReadMode::Dual => "dual",
+            SpiReadMode::Quad => "quad",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<SpiReadMode> {
+        match name {
+            "standard" => Some(SpiReadMode::Standard),
+            "fast" => Some(SpiReadMode::Fast),
+            "dual" => Some(SpiReadMode::Dual),
+            "quad" => Some(SpiReadMode::Quad),
+            _ => None,
+        }
+    }
+}
+
+// A bitstream to be placed in flash
+pub struct FlashImagePart {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+// Errors that can occur while building or walking a flash image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlashImageError {
+    // At least one primary image is needed for the jump
+    NoPrimary,
+    // Golden images and multiple primary images aren't supported yet (see above)
+    GoldenUnsupported,
+    MultiplePrimaries,
+    // Two images (or an image and the jump at address 0) overlap
+    Overlap { first: u32, second: u32 },
+    // Data in the flash image that isn't a bitstream
+    UnknownData { offset: usize },
+    // Bitstream starting at `offset` failed to parse
+    Bitstream {
+        offset: usize,
+        error: BitstreamError,
+    },
+    // Malformed Intel HEX record
+    InvalidHex { line: usize },
+}
+
+impl fmt::Display for FlashImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashImageError::NoPrimary => write!(f, "no primary image given"),
+            FlashImageError::GoldenUnsupported => {
+                write!(f, "golden images aren't supported yet, as the fallback mechanism is unknown")
+            }
+            FlashImageError::MultiplePrimaries => {
+                write!(f, "only one primary image is supported, as only it is jumped to")
+            }
+            FlashImageError::Overlap { first, second } => write!(
+                f,
+                "image at 0x{:08X} overlaps image at 0x{:08X}",
+                first, second
+            ),
+            FlashImageError::UnknownData { offset } => {
+                write!(f, "unrecognised data at offset 0x{:08X}", offset)
+            }
+            FlashImageError::Bitstream { offset, error } => {
+                write!(f, "in image at 0x{:08X}: {}", offset, error)
+            }
+            FlashImageError::InvalidHex { line } => {
+                write!(f, "invalid Intel HEX record on line {}", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlashImageError {}
+
+// Combine a golden image and primary images into a flash image, with a jump
+// to the primary image at address 0. Currently only a single primary image,
+// and no golden image, can be given (see above)
+pub fn build_flash_image(
+    golden: Option<&FlashImagePart>,
+    primaries: &[FlashImagePart],
+    mode: SpiReadMode,
+) -> Result<Vec<u8>, FlashImageError> {
+    if golden.is_some() {
+        return Err(FlashImageError::GoldenUnsupported);
+    }
+    let primary = match primaries {
+        [] => return Err(FlashImageError::NoPrimary),
+        [primary] => primary,
+        _ => return Err(FlashImageError::MultiplePrimaries),
+    };
+    let jump = FlashImagePart {
+        address: 0,
+        data: BitstreamParser::serialise_jump(mode.opcode(), primary.address),
+    };
+    let mut parts = [&jump, primary];
+    parts.sort_by_key(|p| p.address);
+    for pair in parts.windows(2) {
+        let end = pair[0].address as usize + pair[0].data.len();
+        if end > pair[1].address as usize {
+            return Err(FlashImageError::Overlap {
+                first: pair[0].address,
+                second: pair[1].address,
+            });
+        }
+    }
+    let last = parts.last().unwrap();
+    let mut image = vec![0xFF; last.address as usize + last.data.len()];
+    for p in parts.iter() {
+        let start = p.address as usize;
+        image[start..start + p.data.len()].copy_from_slice(&p.data);
+    }
+    Ok(image)
+}
+
+// What was found in a flash image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlashImageContent {
+    // Jump bitstream; mode is the SPI read opcode, if any was set
+    Jump { mode: Option<u8>, address: u32 },
+    // Configuration bitstream
+    Bitstream {
+        idcode: Option<u32>,
+        metadata: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlashImageEntry {
+    pub offset: usize,
+    pub length: usize,
+    pub content: FlashImageContent,
+}
+
+// Walk a flash image, listing the bitstreams inside it
+pub fn list_flash_images(
+    db: &mut Database,
+    data: &[u8],
+) -> Result<Vec<FlashImageEntry>, FlashImageError> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    loop {
+        // Skip erased flash
+        while pos < data.len() && data[pos] == 0xFF {
+            pos += 1;
+        }
+        if pos >= data.len() {
+            break;
+        }
+        let start = BitstreamParser::find_bitstream_start(data, pos)
+            .ok_or(FlashImageError::UnknownData { offset: pos })?;
+        let to_flash_err = |error| FlashImageError::Bitstream {
+            offset: start,
+            error,
+        };
+        let mut parser = BitstreamParser::new(&data[start..]);
+        let mut length = 0;
+        let mut idcode = None;
+        let mut mode = None;
+        let mut jump = None;
+        for dc in parser.commands(db).map_err(to_flash_err)? {
+            let dc = dc.map_err(to_flash_err)?;
+            if let BitstreamCommand::Padding { .. } = dc.command {
+                continue;
+            }
+            length = dc.offset + dc.length;
+            match dc.command {
+                BitstreamCommand::VerifyId { idcode: i } => idcode = Some(i),
+                BitstreamCommand::SpiMode { mode: m } => mode = Some(m),
+                BitstreamCommand::Jump { address } => {
+                    jump = Some(address);
+                    break;
+                }
+                BitstreamCommand::ProgramDone => break,
+                _ => {}
+            }
+        }
+        let content = match jump {
+            Some(address) => FlashImageContent::Jump { mode, address },
+            None => FlashImageContent::Bitstream {
+                idcode,
+                metadata: parser
+                    .get_metadata()
+                    .iter()
+                    .filter(|m| !m.is_empty())
+                    .cloned()
+                    .collect(),
+            },
+        };
+        entries.push(FlashImageEntry {
+            offset: start,
+            length,
+            content,
+        });
+        pos = start + length;
+    }
+    Ok(entries)
+}
+
+// Write a flash image as Intel HEX, omitting fully erased records
+pub fn write_intel_hex(data: &[u8], out: &mut dyn Write) -> std::io::Result<()> {
+    let write_record = |out: &mut dyn Write, addr: u16, rtype: u8, bytes: &[u8]| {
+        let mut record = vec![bytes.len() as u8, (addr >> 8) as u8, (addr & 0xFF) as u8, rtype];
+        record.extend_from_slice(bytes);
+        let checksum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)).wrapping_neg();
+        record.push(checksum);
+        writeln!(out, ":{}", record.iter().map(|b| format!("{:02X}", b)).collect::<String>())
+    };
+    let mut upper = None;
+    for (i, chunk) in data.chunks(16).enumerate() {
+        if chunk.iter().all(|&b| b == 0xFF) {
+            continue;
+        }
+        let addr = i * 16;
+        let addr_upper = (addr >> 16) as u16;
+        if upper != Some(addr_upper) {
+            write_record(out, 0, 0x04, &addr_upper.to_be_bytes())?;
+            upper = Some(addr_upper);
+        }
+        write_record(out, (addr & 0xFFFF) as u16, 0x00, chunk)?;
+    }
+    write_record(out, 0, 0x01, &[])
+}
+
+// Read an Intel HEX file into a flash image, filling gaps with 0xFF
+pub fn read_intel_hex(text: &str) -> Result<Vec<u8>, FlashImageError> {
+    let mut data = Vec::new();
+    let mut base = 0usize;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = FlashImageError::InvalidHex { line: i + 1 };
+        if !line.starts_with(':') || line.len() % 2 != 1 {
+            return Err(err);
+        }
+        let record = (1..line.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&line[j..j + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err.clone())?;
+        if record.len() < 5
+            || record.len() != (record[0] as usize) + 5
+            || record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0
+        {
+            return Err(err);
+        }
+        let addr = ((record[1] as usize) << 8) | (record[2] as usize);
+        let bytes = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => {
+                let start = base + addr;
+                if data.len() < start + bytes.len() {
+                    data.resize(start + bytes.len(), 0xFF);
+                }
+                data[start..start + bytes.len()].copy_from_slice(bytes);
+            }
+            0x01 => break,
+            0x02 if bytes.len() == 2 => {
+                base = (((bytes[0] as usize) << 8) | (bytes[1] as usize)) << 4
+            }
+            0x04 if bytes.len() == 2 => {
+                base = (((bytes[0] as usize) << 8) | (bytes[1] as usize)) << 16
+            }
+            0x03 | 0x05 => {}
+            _ => return Err(err),
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip::Chip;
+    use crate::database::test_database;
+
+    #[test]
+    fn flash_image_roundtrip() {
+        let mut db = test_database();
+        let mut chip = Chip::from_name(&mut db, "TEST");
+        chip.metadata.push("Part: TEST".to_string());
+        let bitstream = BitstreamParser::serialise_chip(&chip);
+        let primary = FlashImagePart {
+            address: 0x1000,
+            data: bitstream.clone(),
+        };
+        let image = build_flash_image(None, &[primary], SpiReadMode::Quad).unwrap();
+        assert_eq!(image.len(), 0x1000 + bitstream.len());
+        let entries = list_flash_images(&mut db, &image).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].offset, 0);
+        assert_eq!(
+            entries[0].content,
+            FlashImageContent::Jump {
+                mode: Some(0x6B),
+                address: 0x1000
+            }
+        );
+        assert_eq!(entries[1].offset, 0x1000);
+        assert_eq!(
+            entries[1].content,
+            FlashImageContent::Bitstream {
+                idcode: Some(chip.get_idcode()),
+                metadata: vec!["Part: TEST".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn flash_image_rejected() {
+        let part = |address: u32, len: usize| FlashImagePart {
+            address,
+            data: vec![0x00; len],
+        };
+        let build = |golden: Option<&FlashImagePart>, primaries: &[FlashImagePart]| {
+            build_flash_image(golden, primaries, SpiReadMode::Fast).err()
+        };
+        assert_eq!(build(None, &[]), Some(FlashImageError::NoPrimary));
+        assert_eq!(build(Some(&part(0x100000, 16)), &[part(0x1000, 16)]), Some(FlashImageError::GoldenUnsupported));
+        assert_eq!(build(None, &[part(0x1000, 16), part(0x2000, 16)]), Some(FlashImageError::MultiplePrimaries));
+        assert_eq!(build(None, &[part(0x10, 16)]), Some(FlashImageError::Overlap { first: 0, second: 0x10 }));
+    }
+
+    #[test]
+    fn intel_hex_roundtrip() {
+        // Crosses a 64KiB boundary, so needs extended address records, with
+        // erased records skipped
+        let mut data = vec![0xFF; 0x10020];
+        for (i, b) in data[0x10..0x25].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        for (i, b) in data[0xFFF8..0x10020].iter_mut().enumerate() {
+            *b = (i as u8).wrapping_mul(7);
+        }
+        let mut hex = Vec::new();
+        write_intel_hex(&data, &mut hex).unwrap();
+        let hex = String::from_utf8(hex).unwrap();
+        assert_eq!(hex.lines().filter(|l| l.starts_with(":02000004")).count(), 2);
+        assert!(hex.ends_with(":00000001FF\n"));
+        assert_eq!(read_intel_hex(&hex).unwrap(), data);
+        // Corrupt the checksum of the second record
+        let bad: String = hex
+            .lines()
+            .enumerate()
+            .map(|(i, l)| if i == 1 { format!("{}00\n", &l[..l.len() - 2]) } else { format!("{}\n", l) })
+            .collect();
+        assert_eq!(read_intel_hex(&bad), Err(FlashImageError::InvalidHex { line: 2 }));
+    }
+}
@@ -0,0 +1,253 @@
+use crate::chip::Chip;
+
+use std::io::{Error, ErrorKind, Result, Write};
+
+// SVF generation for programming over JTAG
+//
+// The bitstream is shifted in with LSC_BITSTREAM_BURST, either directly into
+// SRAM, or via SPI passthrough into the configuration flash, followed by a
+// refresh to load it. Output contains no dates or paths so it can be golden-tested.
+
+// JTAG instructions (8 bit IR)
+const IDCODE_PUB: u8 = 0xE0;
+const ISC_ENABLE: u8 = 0xC6;
+const ISC_ERASE: u8 = 0x0E;
+const ISC_DISABLE: u8 = 0x26;
+const ISC_NOOP: u8 = 0xFF;
+const LSC_INIT_ADDRESS: u8 = 0x46;
+const LSC_BITSTREAM_BURST: u8 = 0x7A;
+const LSC_READ_STATUS: u8 = 0x3C;
+const LSC_REFRESH: u8 = 0x79;
+const LSC_PROG_SPI: u8 = 0x3A;
+
+// Status register bits
+const STATUS_DONE: u32 = 0x00000100;
+const STATUS_BUSY: u32 = 0x00001000;
+const STATUS_FAIL: u32 = 0x00002000;
+
+// SPI flash commands
+const SPI_WRITE_ENABLE: u8 = 0x06;
+const SPI_SECTOR_ERASE: u8 = 0xD8;
+const SPI_PAGE_PROGRAM: u8 = 0x02;
+const SPI_SECTOR_SIZE: usize = 65536;
+const SPI_PAGE_SIZE: usize = 256;
+// Flash commands take a 24 bit address
+const SPI_ADDRESS_LIMIT: usize = 1 << 24;
+// The flash busy (WIP) bit can't be polled, as SVF has no loops, so erase and
+// program are followed by fixed delays covering the datasheet maximum of
+// common 64k sector SPI flashes (3s sector erase, 5ms page program)
+const SPI_SECTOR_ERASE_TIME: &str = "3.00E+00";
+const SPI_PAGE_PROGRAM_TIME: &str = "5.00E-03";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SvfTarget {
+    // Configure the FPGA SRAM directly
+    Sram,
+    // Program the SPI configuration flash through the FPGA, starting at
+    // `address` which must be a multiple of the 64k sector size. The whole
+    // bitstream must be within the first 16MiB of flash
+    Flash { address: u32 },
+}
+
+// Write a SVF file programming `bitstream`, which was generated for `ch`
+pub fn write_svf(ch: &Chip, bitstream: &[u8], target: SvfTarget, out: &mut dyn Write) -> Result<()> {
+    if let SvfTarget::Flash { address } = target {
+        check_flash_range(address as usize, bitstream.len())?;
+    }
+    writeln!(out, "! Generated by prjoxide")?;
+    writeln!(out, "! Device: {}", ch.device)?;
+    writeln!(out, "! Bitstream: {} bytes", bitstream.len())?;
+    writeln!(out)?;
+    writeln!(out, "ENDIR IDLE;")?;
+    writeln!(out, "ENDDR IDLE;")?;
+    writeln!(out, "STATE RESET;")?;
+    writeln!(out, "STATE IDLE;")?;
+    writeln!(out)?;
+    writeln!(out, "! Check IDCODE")?;
+    write_ir(out, IDCODE_PUB)?;
+    writeln!(
+        out,
+        "SDR 32 TDI (00000000) TDO ({:08X}) MASK (FFFFFFFF);",
+        ch.get_idcode()
+    )?;
+    writeln!(out)?;
+    writeln!(out, "! Enable programming mode")?;
+    write_ir(out, ISC_ENABLE)?;
+    writeln!(out, "SDR 8 TDI (00);")?;
+    writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-02 SEC;")?;
+    writeln!(out)?;
+    writeln!(out, "! Erase SRAM")?;
+    write_ir(out, ISC_ERASE)?;
+    writeln!(out, "SDR 8 TDI (01);")?;
+    writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-01 SEC;")?;
+    write_status_check(out, 0, STATUS_BUSY | STATUS_FAIL)?;
+    writeln!(out)?;
+    match target {
+        SvfTarget::Sram => {
+            writeln!(out, "! Program SRAM")?;
+            write_ir(out, LSC_INIT_ADDRESS)?;
+            writeln!(out, "SDR 8 TDI (01);")?;
+            writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-02 SEC;")?;
+            write_ir(out, LSC_BITSTREAM_BURST)?;
+            writeln!(out, "RUNTEST IDLE 2 TCK;")?;
+            write_dr(out, bitstream)?;
+            writeln!(out, "RUNTEST IDLE 100 TCK 1.00E-02 SEC;")?;
+            writeln!(out)?;
+            writeln!(out, "! Exit programming mode")?;
+            write_ir(out, ISC_DISABLE)?;
+            writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-03 SEC;")?;
+            write_ir(out, ISC_NOOP)?;
+            writeln!(out, "RUNTEST IDLE 100 TCK 1.00E-02 SEC;")?;
+        }
+        SvfTarget::Flash { address } => {
+            let start = address as usize;
+            writeln!(out, "! Enable SPI passthrough")?;
+            write_ir(out, LSC_PROG_SPI)?;
+            writeln!(out, "SDR 16 TDI (68FE);")?;
+            writeln!(out, "RUNTEST IDLE 32 TCK;")?;
+            writeln!(out)?;
+            // Each SPI transaction is a single data register scan
+            for sector in (start..(start + bitstream.len())).step_by(SPI_SECTOR_SIZE) {
+                writeln!(out, "! Erase sector at 0x{:06X}", sector)?;
+                write_dr(out, &[SPI_WRITE_ENABLE])?;
+                write_dr(out, &spi_address_command(SPI_SECTOR_ERASE, sector, &[]))?;
+                writeln!(out, "RUNTEST IDLE 32 TCK {} SEC;", SPI_SECTOR_ERASE_TIME)?;
+            }
+            writeln!(out)?;
+            for (i, page) in bitstream.chunks(SPI_PAGE_SIZE).enumerate() {
+                writeln!(out, "! Program page at 0x{:06X}", start + i * SPI_PAGE_SIZE)?;
+                write_dr(out, &[SPI_WRITE_ENABLE])?;
+                write_dr(out, &spi_address_command(SPI_PAGE_PROGRAM, start + i * SPI_PAGE_SIZE, page))?;
+                writeln!(out, "RUNTEST IDLE 32 TCK {} SEC;", SPI_PAGE_PROGRAM_TIME)?;
+            }
+            writeln!(out)?;
+            writeln!(out, "! Exit programming mode and load from flash")?;
+            write_ir(out, ISC_DISABLE)?;
+            writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-03 SEC;")?;
+            write_ir(out, LSC_REFRESH)?;
+            writeln!(out, "RUNTEST IDLE 2 TCK 1.00E-01 SEC;")?;
+            write_ir(out, ISC_NOOP)?;
+            writeln!(out, "RUNTEST IDLE 100 TCK 1.00E-02 SEC;")?;
+        }
+    }
+    writeln!(out)?;
+    writeln!(out, "! Check DONE")?;
+    write_status_check(out, STATUS_DONE, STATUS_DONE | STATUS_FAIL)?;
+    Ok(())
+}
+
+// Check a flash image is sector aligned and can be addressed, before any
+// output is written
+fn check_flash_range(start: usize, len: usize) -> Result<()> {
+    let sector_offset = start % SPI_SECTOR_SIZE;
+    if sector_offset != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("flash address 0x{:08X} is not sector aligned", start),
+        ));
+    }
+    if start + len > SPI_ADDRESS_LIMIT {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "bitstream at flash address 0x{:08X} ({} bytes) extends beyond the 24 bit address space",
+                start, len
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn write_ir(out: &mut dyn Write, ir: u8) -> Result<()> {
+    writeln!(out, "SIR 8 TDI ({:02X});", ir)
+}
+
+fn write_status_check(out: &mut dyn Write, expected: u32, mask: u32) -> Result<()> {
+    write_ir(out, LSC_READ_STATUS)?;
+    writeln!(
+        out,
+        "SDR 32 TDI (00000000) TDO ({:08X}) MASK ({:08X});",
+        expected, mask
+    )
+}
+
+// Write a data register scan of bytes that are sent MSB first. SVF vectors are
+// shifted LSB first from the rightmost digit, so bytes are bit reversed and
+// written in reverse order
+fn write_dr(out: &mut dyn Write, data: &[u8]) -> Result<()> {
+    let hex: String = data
+        .iter()
+        .rev()
+        .map(|b| format!("{:02X}", b.reverse_bits()))
+        .collect();
+    if hex.len() <= 64 {
+        return writeln!(out, "SDR {} TDI ({});", data.len() * 8, hex);
+    }
+    writeln!(out, "SDR {} TDI (", data.len() * 8)?;
+    for i in (0..hex.len()).step_by(64) {
+        writeln!(out, "\t{}", &hex[i..std::cmp::min(i + 64, hex.len())])?;
+    }
+    writeln!(out, "\t);")
+}
+
+fn spi_address_command(cmd: u8, address: usize, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![
+        cmd,
+        ((address >> 16) & 0xFF) as u8,
+        ((address >> 8) & 0xFF) as u8,
+        (address & 0xFF) as u8,
+    ];
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_database;
+
+    // A bitstream spanning two flash pages, with a recognisable start and end
+    fn test_bitstream() -> Vec<u8> {
+        let mut bs = vec![0x00; SPI_PAGE_SIZE + 2];
+        bs[0] = 0x01;
+        bs[SPI_PAGE_SIZE + 1] = 0x80;
+        bs
+    }
+
+    fn svf_string(target: SvfTarget, bitstream: &[u8]) -> Result<String> {
+        let ch = Chip::from_name(&mut test_database(), "TEST");
+        let mut out = Vec::new();
+        write_svf(&ch, bitstream, target, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn svf_golden() {
+        let bs = test_bitstream();
+        assert_eq!(
+            svf_string(SvfTarget::Sram, &bs).unwrap(),
+            include_str!("../testdata/svf/sram.svf")
+        );
+        // Last sector of a 16MiB flash
+        assert_eq!(
+            svf_string(SvfTarget::Flash { address: 0x00FF0000 }, &bs).unwrap(),
+            include_str!("../testdata/svf/flash.svf")
+        );
+    }
+
+    #[test]
+    fn svf_flash_range() {
+        let ch = Chip::from_name(&mut test_database(), "TEST");
+        let bs = test_bitstream();
+        for &address in &[0x00010100, 0x00FFFF00, 0x01000000] {
+            let mut out = Vec::new();
+            let err = write_svf(&ch, &bs, SvfTarget::Flash { address }, &mut out).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert!(out.is_empty());
+        }
+        let mut out = Vec::new();
+        let fits = vec![0x00; SPI_SECTOR_SIZE];
+        write_svf(&ch, &fits, SvfTarget::Flash { address: 0x00FF0000 }, &mut out).unwrap();
+        assert!(!out.is_empty());
+    }
+}